@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE datastore;
+DROP TABLE outbox;
+DROP TABLE events;
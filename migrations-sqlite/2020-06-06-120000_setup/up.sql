@@ -0,0 +1,22 @@
+-- Mirrors the Postgres migrations in ../migrations
+CREATE TABLE events (
+  sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+  version INTEGER NOT NULL,
+  type TEXT NOT NULL,
+  data TEXT NOT NULL,
+  timestamp TIMESTAMP NOT NULL,
+  stream TEXT,
+  revision INTEGER,
+  CHECK ((stream IS NULL) = (revision IS NULL))
+);
+
+CREATE UNIQUE INDEX events_stream_revision_idx ON events (stream, revision) WHERE stream IS NOT NULL;
+
+CREATE TABLE outbox (
+  sequence INTEGER PRIMARY KEY REFERENCES events (sequence) ON DELETE CASCADE
+);
+
+CREATE TABLE datastore (
+  hash BLOB PRIMARY KEY,
+  data BLOB NOT NULL
+);
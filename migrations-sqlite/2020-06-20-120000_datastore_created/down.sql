@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE datastore_old (
+  hash BLOB PRIMARY KEY,
+  data BLOB NOT NULL
+);
+INSERT INTO datastore_old SELECT hash, data FROM datastore;
+DROP TABLE datastore;
+ALTER TABLE datastore_old RENAME TO datastore;
@@ -0,0 +1,11 @@
+-- Lets garbage collection spare blobs that are still waiting for their event.
+-- Only constant defaults can be added, so the table is rebuilt to stamp the
+-- rows from before with the time of the migration, as in Postgres.
+CREATE TABLE datastore_new (
+  hash BLOB PRIMARY KEY,
+  data BLOB NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO datastore_new (hash, data) SELECT hash, data FROM datastore;
+DROP TABLE datastore;
+ALTER TABLE datastore_new RENAME TO datastore;
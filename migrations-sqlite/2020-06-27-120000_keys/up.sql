@@ -0,0 +1,4 @@
+CREATE TABLE keys (
+  subject TEXT PRIMARY KEY,
+  key BLOB NOT NULL
+);
@@ -0,0 +1,26 @@
+-- This file should undo anything in `up.sql`
+-- Rebuilds the table, as only SQLite 3.35 and later can drop columns.
+-- Dropping it would take the outbox along, so that is kept aside.
+CREATE TABLE outbox_kept AS SELECT sequence FROM outbox;
+
+CREATE TABLE events_rebuilt (
+  sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+  version INTEGER NOT NULL,
+  type TEXT NOT NULL,
+  data TEXT NOT NULL,
+  timestamp TIMESTAMP NOT NULL,
+  stream TEXT,
+  revision INTEGER,
+  CHECK ((stream IS NULL) = (revision IS NULL))
+);
+
+INSERT INTO events_rebuilt (sequence, version, type, data, timestamp, stream, revision)
+  SELECT sequence, version, type, data, timestamp, stream, revision FROM events;
+
+DROP TABLE events;
+ALTER TABLE events_rebuilt RENAME TO events;
+
+CREATE UNIQUE INDEX events_stream_revision_idx ON events (stream, revision) WHERE stream IS NOT NULL;
+
+INSERT OR IGNORE INTO outbox (sequence) SELECT sequence FROM outbox_kept;
+DROP TABLE outbox_kept;
@@ -0,0 +1,2 @@
+-- Chains each event to its predecessor, NULL for events from before
+ALTER TABLE events ADD COLUMN hash BLOB;
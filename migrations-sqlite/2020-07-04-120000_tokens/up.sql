@@ -0,0 +1,5 @@
+-- API tokens by their Blake2s hash, with space-separated scopes
+CREATE TABLE tokens (
+  hash BLOB PRIMARY KEY,
+  scopes TEXT NOT NULL
+);
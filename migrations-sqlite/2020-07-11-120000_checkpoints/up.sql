@@ -0,0 +1,8 @@
+-- Signed heads of the Merkle tree over the events, by tree size
+CREATE TABLE checkpoints (
+  size BIGINT PRIMARY KEY,
+  root TEXT NOT NULL,
+  timestamp TIMESTAMP NOT NULL,
+  key TEXT NOT NULL,
+  signature TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX events_stream_revision_idx;
+
+ALTER TABLE events
+  DROP CONSTRAINT events_stream_revision,
+  DROP COLUMN revision,
+  DROP COLUMN stream;
@@ -0,0 +1,6 @@
+ALTER TABLE events
+  ADD COLUMN stream varchar,
+  ADD COLUMN revision bigint,
+  ADD CONSTRAINT events_stream_revision CHECK ((stream IS NULL) = (revision IS NULL));
+
+CREATE UNIQUE INDEX events_stream_revision_idx ON events (stream, revision) WHERE stream IS NOT NULL;
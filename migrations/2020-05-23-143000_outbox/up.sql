@@ -0,0 +1,3 @@
+CREATE TABLE outbox (
+  sequence bigint PRIMARY KEY REFERENCES events (sequence) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Lets garbage collection spare blobs that are still waiting for their event
+ALTER TABLE datastore ADD COLUMN created timestamptz NOT NULL DEFAULT now();
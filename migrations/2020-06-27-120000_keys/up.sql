@@ -0,0 +1,4 @@
+CREATE TABLE keys (
+  subject varchar PRIMARY KEY,
+  key bytea NOT NULL
+);
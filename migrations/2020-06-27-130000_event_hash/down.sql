@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE event_head;
+ALTER TABLE events DROP COLUMN hash;
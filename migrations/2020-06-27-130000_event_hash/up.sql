@@ -0,0 +1,12 @@
+-- Chains each event to its predecessor, NULL for events from before
+ALTER TABLE events ADD COLUMN hash bytea;
+
+-- The end of the hash chain, a single row that writers lock in turn
+CREATE TABLE event_head (
+  id boolean PRIMARY KEY DEFAULT true CHECK (id),
+  sequence bigint NOT NULL,
+  hash bytea
+);
+
+-- Events from before aren't chained, so the chain starts after them
+INSERT INTO event_head (sequence) SELECT coalesce(max(sequence), -1) FROM events;
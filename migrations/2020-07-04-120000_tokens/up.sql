@@ -0,0 +1,5 @@
+-- API tokens by their Blake2s hash, with space-separated scopes
+CREATE TABLE tokens (
+  hash bytea PRIMARY KEY,
+  scopes varchar NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Signed heads of the Merkle tree over the events, by tree size
+CREATE TABLE checkpoints (
+  size bigint PRIMARY KEY,
+  root varchar NOT NULL,
+  timestamp timestamptz NOT NULL,
+  key varchar NOT NULL,
+  signature varchar NOT NULL
+);
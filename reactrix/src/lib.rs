@@ -0,0 +1,55 @@
+// This file is part of reactrix.
+//
+// Copyright 2019-2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types shared between the reactrix store and its clients
+
+// Diesel 1.4's derives expand to impls newer compilers lint
+#![allow(non_local_definitions, unexpected_cfgs)]
+
+#[macro_use]
+extern crate diesel;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An event as it was stored, with its global sequence
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Queryable)]
+pub struct Event {
+    pub sequence: i64,
+    pub version: i32,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub data: Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// An event as submitted for storage
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NewEvent {
+    pub version: i32,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub data: Value,
+}
+
+/// Envelope of every response of the HTTP API
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum ApiResult<T> {
+    Ok { data: T },
+    Error { reason: String },
+}
@@ -0,0 +1,210 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod file;
+mod mongo;
+mod postgres;
+mod sqlite;
+
+use crate::eventstore::{EventStoreError, Result};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+pub use file::*;
+use log::error;
+pub use mongo::*;
+pub use postgres::*;
+use sodiumoxide::randombytes;
+pub use sqlite::*;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reject::{self, Reject, Rejection};
+use warp::Filter;
+
+pub const EVENTS_READ: &str = "events:read";
+pub const EVENTS_WRITE: &str = "events:write";
+pub const DATA_READ: &str = "data:read";
+pub const DATA_WRITE: &str = "data:write";
+pub const KEYS_DESTROY: &str = "keys:destroy";
+pub const MESSAGE_PUBLISH: &str = "message:publish";
+
+const BEARER: &str = "bearer";
+const TOKEN_SIZE: usize = 32;
+
+/// Knows the scopes granted to API tokens, which it only sees hashed
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Nothing for unknown tokens
+    async fn scopes(&self, hash: &[u8]) -> Result<Option<Vec<String>>>;
+}
+
+/// A request turned away, to be answered with `status`
+#[derive(Debug)]
+pub struct Denied {
+    pub status: StatusCode,
+    pub reason: String,
+}
+
+impl Reject for Denied {}
+
+fn deny(status: StatusCode, reason: String) -> Rejection {
+    reject::custom(Denied { status, reason })
+}
+
+pub fn hash(token: &str) -> Vec<u8> {
+    Blake2s::digest(token.as_bytes()).to_vec()
+}
+
+/// A fresh random token
+pub fn generate() -> Result<String> {
+    sodiumoxide::init()
+        .map_err(|_| EventStoreError::Database("Couldn't initialize libsodium".to_string()))?;
+    Ok(hex::encode(randombytes::randombytes(TOKEN_SIZE)))
+}
+
+/// The token of a bearer `Authorization` header, whose scheme is
+/// case-insensitive as per RFC 7235
+fn bearer(header: &str) -> Option<&str> {
+    let mut parts = header.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case(BEARER) => {
+            Some(token.trim()).filter(|token| !token.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Make sure that `token` was granted `scope`
+pub async fn check(
+    tokens: &dyn TokenStore,
+    token: &str,
+    scope: &'static str,
+) -> std::result::Result<(), Denied> {
+    let denied = |status, reason| Err(Denied { status, reason });
+
+    match tokens.scopes(&hash(token)).await {
+        Ok(Some(scopes)) if scopes.iter().any(|granted| granted == scope) => Ok(()),
+        Ok(Some(_)) => denied(StatusCode::FORBIDDEN, format!("Token lacks scope {}", scope)),
+        Ok(None) => denied(StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+        Err(e) => {
+            // The details are for the log only
+            error!("Couldn't look up token: {}", e);
+            denied(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Couldn't check token".to_string(),
+            )
+        }
+    }
+}
+
+async fn authorize(
+    tokens: Option<Arc<dyn TokenStore>>,
+    header: Option<String>,
+    scope: &'static str,
+) -> std::result::Result<(), Rejection> {
+    let tokens = match tokens {
+        Some(tokens) => tokens,
+        None => return Ok(()),
+    };
+
+    match header.as_deref().and_then(bearer) {
+        Some(token) => check(tokens.as_ref(), token, scope)
+            .await
+            .map_err(reject::custom),
+        None => Err(deny(
+            StatusCode::UNAUTHORIZED,
+            "Missing bearer token".to_string(),
+        )),
+    }
+}
+
+/// Admit requests whose bearer token was granted `scope`, or any request at
+/// all without a token store, which takes `--no-auth`
+pub fn require(
+    tokens: Option<Arc<dyn TokenStore>>,
+    scope: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header| authorize(tokens.clone(), header, scope))
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "secret";
+
+    struct Tokens;
+
+    #[async_trait]
+    impl TokenStore for Tokens {
+        async fn scopes(&self, token: &[u8]) -> Result<Option<Vec<String>>> {
+            if token == &hash(TOKEN)[..] {
+                Ok(Some(vec![EVENTS_READ.to_string()]))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    async fn status(scope: &'static str, header: Option<&str>) -> StatusCode {
+        let filter = require(Some(Arc::new(Tokens)), scope).map(|| StatusCode::OK);
+        let request = match header {
+            Some(header) => warp::test::request().header("authorization", header),
+            None => warp::test::request(),
+        };
+
+        match request.filter(&filter).await {
+            Ok(status) => status,
+            Err(rejection) => rejection.find::<Denied>().unwrap().status,
+        }
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert_eq!(bearer("Bearer secret"), Some("secret"));
+        assert_eq!(bearer("bearer secret"), Some("secret"));
+        assert_eq!(bearer(" BEARER  secret "), Some("secret"));
+        assert_eq!(bearer("Bearer "), None);
+        assert_eq!(bearer("Basic secret"), None);
+        assert_eq!(bearer("Bearersecret"), None);
+    }
+
+    #[tokio::test]
+    async fn tokens_need_the_scope() {
+        assert_eq!(status(EVENTS_READ, Some("Bearer secret")).await, StatusCode::OK);
+        assert_eq!(status(EVENTS_READ, Some("bearer secret")).await, StatusCode::OK);
+        assert_eq!(
+            status(EVENTS_WRITE, Some("Bearer secret")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_without_valid_tokens_are_refused() {
+        let unauthorized = StatusCode::UNAUTHORIZED;
+        assert_eq!(status(EVENTS_READ, None).await, unauthorized);
+        assert_eq!(status(EVENTS_READ, Some("Bearer wrong")).await, unauthorized);
+        assert_eq!(status(EVENTS_READ, Some("Basic secret")).await, unauthorized);
+    }
+
+    #[tokio::test]
+    async fn no_auth_admits_anyone() {
+        let filter = require(None, EVENTS_WRITE);
+        assert!(warp::test::request().matches(&filter).await);
+    }
+}
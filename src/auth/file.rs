@@ -0,0 +1,64 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::TokenStore;
+use crate::eventstore::{EventStoreError, Result};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs;
+use url::Url;
+
+/// Tokens from a file with one per line: the hex encoded hash followed by
+/// the scopes, separated by whitespace. Blank lines and lines starting with
+/// `#` are ignored. The file is read once on startup.
+pub struct FileTokenStore(HashMap<Vec<u8>, Vec<String>>);
+
+impl FileTokenStore {
+    pub fn open(url: &Url) -> Result<Self> {
+        let path = url
+            .to_file_path()
+            .map_err(|_| EventStoreError::Database(format!("Invalid path in {}", url)))?;
+
+        let mut tokens = HashMap::new();
+        for (number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let hash = match fields.next() {
+                Some(hash) if !hash.starts_with('#') => hash,
+                _ => continue,
+            };
+
+            let hash = hex::decode(hash).map_err(|e| {
+                EventStoreError::Database(format!(
+                    "Invalid token hash in line {} of {}: {}",
+                    number + 1,
+                    path.display(),
+                    e
+                ))
+            })?;
+            tokens.insert(hash, fields.map(str::to_string).collect());
+        }
+
+        Ok(Self(tokens))
+    }
+}
+
+#[async_trait]
+impl TokenStore for FileTokenStore {
+    async fn scopes(&self, hash: &[u8]) -> Result<Option<Vec<String>>> {
+        Ok(self.0.get(hash).cloned())
+    }
+}
@@ -0,0 +1,55 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::TokenStore;
+use crate::eventstore::Result;
+
+use async_trait::async_trait;
+use bson::{doc, Bson};
+use mongodb::Database;
+
+const TOKENS: &str = "tokens";
+
+/// Tokens are documents with the hex encoded hash as their ID and an array
+/// of scopes
+pub struct MongoTokenStore(Database);
+
+impl MongoTokenStore {
+    pub fn new(database: Database) -> Self {
+        Self(database)
+    }
+}
+
+#[async_trait]
+impl TokenStore for MongoTokenStore {
+    async fn scopes(&self, hash: &[u8]) -> Result<Option<Vec<String>>> {
+        match self
+            .0
+            .collection(TOKENS)
+            .find_one(doc! { "_id": hex::encode(hash) }, None)
+            .await?
+        {
+            Some(doc) => Ok(Some(
+                doc.get_array("scopes")?
+                    .iter()
+                    .filter_map(Bson::as_str)
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::auth::TokenStore;
+use crate::eventstore::Result;
+use crate::schema::tokens::dsl;
+use crate::{blocking, PgPool};
+
+use async_trait::async_trait;
+use diesel::prelude::*;
+use std::sync::Arc;
+
+pub struct PostgresTokenStore(Arc<PgPool>);
+
+impl PostgresTokenStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self(pool)
+    }
+}
+
+#[async_trait]
+impl TokenStore for PostgresTokenStore {
+    async fn scopes(&self, hash: &[u8]) -> Result<Option<Vec<String>>> {
+        let hash = hash.to_vec();
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::tokens
+                .select(dsl::scopes)
+                .filter(dsl::hash.eq(hash))
+                .first::<String>(conn)
+                .optional()?
+                .map(|scopes| scopes.split_whitespace().map(str::to_string).collect()))
+        })
+        .await
+    }
+}
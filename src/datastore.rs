@@ -14,13 +14,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod chunked;
+mod encrypted;
+mod file;
+mod memory;
 mod mongo;
 mod postgres;
+mod s3;
+mod sqlite;
 
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use failure::Fail;
+use futures::channel::oneshot;
+use futures::{future, stream, Stream, TryStreamExt};
 use log::warn;
+pub use chunked::*;
+pub use encrypted::*;
+pub use file::*;
+pub use memory::*;
 pub use mongo::*;
 pub use postgres::*;
+pub use s3::*;
+pub use sqlite::*;
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::task::JoinError;
+
+/// Blob contents in chunks of whatever size they happen to come in
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// ID of a blob that becomes known once all of its stream went through
+pub type PendingId = oneshot::Receiver<Vec<u8>>;
+
+static UNIQUE: AtomicUsize = AtomicUsize::new(0);
+
+/// Biggest blob for backends that hold all of it in memory on its way
+const BUFFERED_LIMIT: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug, Fail)]
 pub enum DataStoreError {
@@ -30,27 +63,299 @@ pub enum DataStoreError {
     NoRecord,
     #[fail(display = "Identical hash for different data detected: {}", 0)]
     Collision(String),
+    #[fail(display = "Blob is bigger than {} bytes", 0)]
+    TooBig(u64),
 }
 
 pub type Result<T> = std::result::Result<T, DataStoreError>;
 
-fn entry_exists(store: &impl DataStore, hash: &[u8], data: &[u8]) -> Result<bool> {
-    match store.retrieve(&hash) {
-        Ok(stored) => {
-            if data == &stored[..] {
-                warn!("Data blob {} is already stored", &hex::encode(hash));
-                return Ok(true);
-            } else {
-                return Err(DataStoreError::Collision(hex::encode(hash)));
+impl From<JoinError> for DataStoreError {
+    fn from(error: JoinError) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+impl From<oneshot::Canceled> for DataStoreError {
+    fn from(_: oneshot::Canceled) -> Self {
+        Self::Database("Stream ended before its ID was known".to_string())
+    }
+}
+
+/// Bytes compared at either end of a blob stored under the same hash before
+const SAMPLE_SIZE: usize = 4096;
+
+/// Size and both ends of a blob, enough to tell a duplicate from a hash
+/// collision without reading all of the stored blob back
+#[derive(Default)]
+struct Sample {
+    size: u64,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl Sample {
+    fn of(data: &[u8]) -> Self {
+        let mut sample = Self::default();
+        sample.update(data);
+        sample
+    }
+
+    /// Take in the next piece of a blob
+    fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+
+        let n = (SAMPLE_SIZE - self.head.len()).min(chunk.len());
+        self.head.extend_from_slice(&chunk[..n]);
+
+        self.tail.extend_from_slice(&chunk[chunk.len().saturating_sub(SAMPLE_SIZE)..]);
+        let excess = self.tail.len().saturating_sub(SAMPLE_SIZE);
+        self.tail.drain(..excess);
+    }
+}
+
+async fn read_range(
+    store: &(impl DataStore + ?Sized),
+    hash: &[u8],
+    offset: u64,
+    length: usize,
+) -> Result<Vec<u8>> {
+    store
+        .retrieve_range(hash, offset, length as u64)
+        .await?
+        .map_ok(|chunk| chunk.to_vec())
+        .try_concat()
+        .await
+}
+
+/// Make sure that the blob already stored under `hash` is the one sampled
+async fn check_duplicate(
+    store: &(impl DataStore + ?Sized),
+    hash: &[u8],
+    sample: &Sample,
+) -> Result<()> {
+    let tail = sample.size - sample.tail.len() as u64;
+
+    if store.size(hash).await? != sample.size
+        || read_range(store, hash, 0, sample.head.len()).await? != sample.head
+        || read_range(store, hash, tail, sample.tail.len()).await? != sample.tail
+    {
+        return Err(DataStoreError::Collision(hex::encode(hash)));
+    }
+
+    warn!("Data blob {} is already stored", &hex::encode(hash));
+    Ok(())
+}
+
+/// Hash and sample a stream on its way through. Both are handed out at its
+/// end, the hash once more as the ID to store it under.
+fn hashing(data: ByteStream) -> (ByteStream, PendingId, oneshot::Receiver<(Vec<u8>, Sample)>) {
+    let (id, pending) = oneshot::channel();
+    let (done, sampled) = oneshot::channel();
+
+    let data = Box::pin(stream::try_unfold(
+        (data, Blake2s::new(), Sample::default(), id, done),
+        |(mut data, mut hasher, mut sample, id, done)| async move {
+            match data.try_next().await? {
+                Some(chunk) => {
+                    hasher.input(&chunk);
+                    sample.update(&chunk);
+                    Ok(Some((chunk, (data, hasher, sample, id, done))))
+                }
+                None => {
+                    let hash = hasher.result().to_vec();
+                    // Whoever stopped listening doesn't need them anymore
+                    let _ = id.send(hash.clone());
+                    let _ = done.send((hash, sample));
+                    Ok(None)
+                }
+            }
+        },
+    ));
+
+    (data, pending, sampled)
+}
+
+/// Skip `offset` bytes of a stream and end it after `length` more
+fn slice_stream(data: ByteStream, offset: u64, length: u64) -> ByteStream {
+    Box::pin(stream::try_unfold(
+        (data, offset, length),
+        |(mut data, mut skip, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+
+            while let Some(mut chunk) = data.try_next().await? {
+                if skip >= chunk.len() as u64 {
+                    skip -= chunk.len() as u64;
+                    continue;
+                }
+
+                let mut chunk = chunk.split_off(skip as usize);
+                chunk.truncate(remaining.min(chunk.len() as u64) as usize);
+                let remaining = remaining - chunk.len() as u64;
+                return Ok(Some((chunk, (data, 0, remaining))));
             }
+
+            Ok::<_, DataStoreError>(None)
+        },
+    ))
+}
+
+/// Name for a blob in the making, before its hash is known
+fn upload_name() -> String {
+    format!(
+        "{}-{}-{}",
+        process::id(),
+        Utc::now().timestamp_nanos(),
+        UNIQUE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[async_trait]
+pub trait DataStore: Send + Sync {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>>;
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>>;
+
+    /// Store a blob under a key other than its own hash, for layers on top
+    /// that keep something else there. Returns `false` and leaves the blob
+    /// alone if there is one under `id` already, also with concurrent calls,
+    /// except that it counts as stored just now for `list`.
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool>;
+
+    /// Store a blob without holding all of it in memory at once
+    async fn store_stream(&self, data: ByteStream) -> Result<Vec<u8>> {
+        let (data, id, sampled) = hashing(data);
+        let stored = self.store_stream_as(data, id).await?;
+
+        let (hash, sample) = sampled.await?;
+        if !stored {
+            check_duplicate(self, &hash, &sample).await?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Store a stream like `store_as`, under an ID that is only known once
+    /// all of it went through. Backends that can't do that collect it first.
+    async fn store_stream_as(&self, mut data: ByteStream, id: PendingId) -> Result<bool> {
+        let mut buffer = Vec::new();
+        while let Some(chunk) = data.try_next().await? {
+            buffer.extend_from_slice(&chunk);
         }
 
-        Err(DataStoreError::NoRecord) => Ok(false),
-        Err(e) => Err(e),
+        self.store_as(&id.await?, &buffer).await
+    }
+
+    /// Retrieve a blob without holding all of it in memory at once.
+    /// Backends that can't do that read it in one go.
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        let data = Bytes::from(self.retrieve(id).await?);
+        Ok(Box::pin(stream::once(future::ready(Ok(data)))))
+    }
+
+    /// Size of a blob in bytes
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        Ok(self.retrieve(id).await?.len() as u64)
+    }
+
+    /// Up to `length` bytes of a blob, starting at `offset`
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        Ok(slice_stream(self.retrieve_stream(id).await?, offset, length))
+    }
+
+    /// Hashes of all blobs stored before `before`; those stored before
+    /// their age was recorded count as old
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>>;
+
+    /// Remove a blob unless it has been stored again since `before`, so
+    /// that one listed as old a moment ago survives if it is about to be
+    /// referenced. Tells whether it was removed.
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool>;
+
+    /// Remove what uploads left behind that were started before `before` and
+    /// never finished, e.g. because the process crashed. Tells how many
+    /// there were.
+    async fn remove_uploads(&self, _before: DateTime<Utc>) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Other blobs that a blob needs to stay around
+    async fn references(&self, _id: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    /// Biggest blob the store takes, if it can't stream blobs and holds
+    /// all of one in memory instead
+    fn max_blob_size(&self) -> Option<u64> {
+        None
     }
 }
 
-pub trait DataStore: Send + Sync {
-    fn store(&self, data: &[u8]) -> Result<Vec<u8>>;
-    fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("reactrix-data-{}-{}", process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chunks(data: &'static [u8]) -> ByteStream {
+        Box::pin(stream::iter(data.chunks(3).map(|chunk| Ok(Bytes::from(chunk)))))
+    }
+
+    async fn duplicates_are_stored_once(store: &dyn DataStore) {
+        let data: &[u8] = b"the same blob";
+        let (a, b) = future::join(store.store(data), store.store(data)).await;
+        let hash = a.unwrap();
+        assert_eq!(b.unwrap(), hash);
+
+        assert!(!store.store_as(&hash, data).await.unwrap());
+        assert_eq!(store.store_stream(chunks(data)).await.unwrap(), hash);
+        assert_eq!(store.retrieve(&hash).await.unwrap(), data);
+    }
+
+    async fn collisions_are_detected(store: &dyn DataStore) {
+        let hash = Blake2s::digest(b"real");
+        assert!(store.store_as(&hash, b"fake").await.unwrap());
+
+        for result in [
+            store.store(b"real").await,
+            store.store_stream(chunks(b"real")).await,
+        ] {
+            assert!(matches!(result, Err(DataStoreError::Collision(_))));
+        }
+        assert_eq!(store.retrieve(&hash).await.unwrap(), b"fake");
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_the_contract() {
+        duplicates_are_stored_once(&MemoryDataStore::new()).await;
+        collisions_are_detected(&MemoryDataStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_keeps_the_contract() {
+        let dir = scratch("sqlite");
+        let open = |name: &str| {
+            let pool = sqlite::connect(dir.join(name).to_str().unwrap()).unwrap();
+            SqliteDataStore::new(Arc::new(pool))
+        };
+        duplicates_are_stored_once(&open("duplicates.db")).await;
+        collisions_are_detected(&open("collisions.db")).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_keeps_the_contract() {
+        let dir = scratch("file");
+        duplicates_are_stored_once(&FileDataStore::new(dir.join("duplicates")).unwrap()).await;
+        collisions_are_detected(&FileDataStore::new(dir.join("collisions")).unwrap()).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
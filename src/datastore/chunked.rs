@@ -0,0 +1,540 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{
+    check_duplicate, slice_stream, ByteStream, DataStore, DataStoreError, Result, Sample,
+};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use rmp_serde as rmp;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// Cut where the top 20 bits of the rolling hash are zero, for chunks of
+/// about 1 MiB past the minimum
+const BOUNDARY_MASK: u64 = 0xfffff << 44;
+/// Appended to the hash of a blob for the key of its manifest. Content
+/// addresses are hashes and thus shorter, so no blob can pose as a manifest.
+const MANIFEST_SUFFIX: &[u8] = b"\0manifest";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Chunk {
+    hash: String,
+    size: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Manifest {
+    size: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl Manifest {
+    fn encode(&self) -> Result<Vec<u8>> {
+        rmp::to_vec_named(self)
+            .map_err(|e| DataStoreError::Database(format!("Couldn't encode manifest: {}", e)))
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let manifest = rmp::from_read_ref::<_, Self>(data)
+            .map_err(|e| DataStoreError::Database(format!("Couldn't decode manifest: {}", e)))?;
+
+        let size = manifest.chunks.iter().map(|chunk| chunk.size).sum::<u64>();
+        if size != manifest.size {
+            return Err(DataStoreError::Database(format!(
+                "Manifest of {} bytes lists chunks of {}",
+                manifest.size, size
+            )));
+        }
+
+        Ok(manifest)
+    }
+}
+
+fn manifest_key(id: &[u8]) -> Vec<u8> {
+    [id, MANIFEST_SUFFIX].concat()
+}
+
+/// Whether `id` is the key of a chunk manifest rather than of a blob
+pub fn is_manifest_key(id: &[u8]) -> bool {
+    id.ends_with(MANIFEST_SUFFIX)
+}
+
+/// Splits big blobs into chunks at boundaries chosen by their content, so
+/// blobs that only differ in places share most of their chunks.
+/// The chunks go to the underlying store under their own hashes, and a
+/// manifest listing them under a key derived from the hash of the whole blob.
+/// Small blobs and those stored before are passed through as they are.
+///
+/// Chunked blobs can only be read through this layer, so stores always go
+/// through it; `split` only decides whether new big blobs are chunked.
+pub struct ChunkedDataStore {
+    inner: Arc<dyn DataStore>,
+    gear: [u64; 256],
+    split: bool,
+}
+
+impl ChunkedDataStore {
+    pub fn new(inner: Arc<dyn DataStore>, split: bool) -> Self {
+        // Fixed pseudo-random values, so boundaries stay put across restarts
+        let mut gear = [0; 256];
+        let mut state = 0x7265_6163_7472_6978_u64;
+        for value in gear.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = z ^ (z >> 31);
+        }
+
+        Self { inner, gear, split }
+    }
+
+    /// Length of the next chunk at the start of `data`, if it can be told
+    /// yet; at the end of a blob, whatever is left is the last chunk
+    fn cut(&self, data: &[u8]) -> Option<usize> {
+        if data.len() <= MIN_CHUNK {
+            return None;
+        }
+
+        let end = data.len().min(MAX_CHUNK);
+        let mut hash = 0u64;
+        for (i, byte) in data[MIN_CHUNK..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(self.gear[*byte as usize]);
+            if hash & BOUNDARY_MASK == 0 {
+                return Some(MIN_CHUNK + i + 1);
+            }
+        }
+
+        if end == MAX_CHUNK {
+            Some(MAX_CHUNK)
+        } else {
+            None
+        }
+    }
+
+    async fn store_chunk(&self, manifest: &mut Manifest, data: &[u8]) -> Result<()> {
+        let hash = self.inner.store(data).await?;
+        manifest.chunks.push(Chunk {
+            hash: hex::encode(hash),
+            size: data.len() as u64,
+        });
+        manifest.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Store all chunks that can be told in `buffer`, and all of it at the end
+    async fn store_chunks(
+        &self,
+        manifest: &mut Manifest,
+        buffer: &mut Vec<u8>,
+        end: bool,
+    ) -> Result<()> {
+        let mut offset = 0;
+        while let Some(length) = self.cut(&buffer[offset..]) {
+            self.store_chunk(manifest, &buffer[offset..offset + length])
+                .await?;
+            offset += length;
+        }
+
+        if end && offset < buffer.len() {
+            self.store_chunk(manifest, &buffer[offset..]).await?;
+            offset = buffer.len();
+        }
+
+        buffer.drain(..offset);
+        Ok(())
+    }
+
+    async fn store_manifest(
+        &self,
+        hash: &[u8],
+        manifest: Manifest,
+        sample: Sample,
+    ) -> Result<Vec<u8>> {
+        if !self.inner.store_as(&manifest_key(hash), &manifest.encode()?).await? {
+            check_duplicate(self, hash, &sample).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    /// The manifest of a chunked blob, nothing for one stored as it is
+    async fn manifest(&self, id: &[u8]) -> Result<Option<Manifest>> {
+        match self.inner.retrieve(&manifest_key(id)).await {
+            Ok(data) => Ok(Some(Manifest::decode(&data)?)),
+            Err(DataStoreError::NoRecord) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The chunks one after the other, each one read whole
+    fn assemble(&self, chunks: Vec<Chunk>) -> ByteStream {
+        let inner = self.inner.clone();
+
+        Box::pin(stream::iter(chunks).then(move |chunk| {
+            let inner = inner.clone();
+            async move {
+                let hash = hex::decode(&chunk.hash).map_err(|e| {
+                    DataStoreError::Database(format!("Invalid chunk {}: {}", chunk.hash, e))
+                })?;
+                Ok(Bytes::from(inner.retrieve(&hash).await?))
+            }
+        }))
+    }
+}
+
+#[async_trait]
+impl DataStore for ChunkedDataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if !self.split || data.len() <= MAX_CHUNK {
+            return self.inner.store(data).await;
+        }
+
+        let hash = Blake2s::digest(data);
+
+        let mut manifest = Manifest::default();
+        self.store_chunks(&mut manifest, &mut data.to_vec(), true)
+            .await?;
+        self.store_manifest(&hash, manifest, Sample::of(data)).await
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        match self.manifest(id).await? {
+            Some(manifest) => {
+                self.assemble(manifest.chunks)
+                    .map_ok(|chunk| chunk.to_vec())
+                    .try_concat()
+                    .await
+            }
+            None => self.inner.retrieve(id).await,
+        }
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        self.inner.store_as(id, data).await
+    }
+
+    async fn store_stream(&self, mut data: ByteStream) -> Result<Vec<u8>> {
+        if !self.split {
+            return self.inner.store_stream(data).await;
+        }
+
+        let mut hasher = Blake2s::new();
+        let mut sample = Sample::default();
+        let mut buffer = Vec::new();
+        let mut manifest = Manifest::default();
+
+        while let Some(chunk) = data.try_next().await? {
+            hasher.input(&chunk);
+            sample.update(&chunk);
+            buffer.extend_from_slice(&chunk);
+
+            // Look for boundaries once there's surely a whole chunk, rather
+            // than rescanning the buffer for every piece of the body; what
+            // fits into one is stored as it is, just as with `store`
+            if buffer.len() > MAX_CHUNK {
+                self.store_chunks(&mut manifest, &mut buffer, false).await?;
+            }
+        }
+
+        if manifest.chunks.is_empty() {
+            return self.inner.store(&buffer).await;
+        }
+
+        self.store_chunks(&mut manifest, &mut buffer, true).await?;
+        self.store_manifest(&hasher.result(), manifest, sample)
+            .await
+    }
+
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        match self.manifest(id).await? {
+            Some(manifest) => Ok(self.assemble(manifest.chunks)),
+            None => self.inner.retrieve_stream(id).await,
+        }
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        match self.manifest(id).await? {
+            Some(manifest) => Ok(manifest.size),
+            None => self.inner.size(id).await,
+        }
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        let manifest = match self.manifest(id).await? {
+            Some(manifest) => manifest,
+            None => return self.inner.retrieve_range(id, offset, length).await,
+        };
+
+        // Only read the chunks overlapping the range
+        let mut skipped = 0;
+        let mut end = 0;
+        let mut chunks = Vec::new();
+        for chunk in manifest.chunks {
+            let start = end;
+            end += chunk.size;
+
+            if end <= offset {
+                skipped = end;
+            } else if start < offset + length {
+                chunks.push(chunk);
+            } else {
+                break;
+            }
+        }
+
+        Ok(slice_stream(self.assemble(chunks), offset - skipped, length))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        self.inner.list(before).await
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        self.inner.remove(id, before).await
+    }
+
+    async fn remove_uploads(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.inner.remove_uploads(before).await
+    }
+
+    /// A blob needs its manifest and the manifest needs the chunks, so that
+    /// a manifest listed by itself keeps its chunks as well
+    async fn references(&self, id: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (blob, mut references) = if is_manifest_key(id) {
+            (&id[..id.len() - MANIFEST_SUFFIX.len()], Vec::new())
+        } else {
+            (id, vec![manifest_key(id)])
+        };
+        let manifest = match self.manifest(blob).await? {
+            Some(manifest) => manifest,
+            None => return Ok(Vec::new()),
+        };
+
+        for chunk in manifest.chunks {
+            references.push(hex::decode(&chunk.hash).map_err(|e| {
+                DataStoreError::Database(format!("Invalid chunk {}: {}", chunk.hash, e))
+            })?);
+        }
+        Ok(references)
+    }
+
+    /// Split blobs only ever pass a chunk at a time to the underlying store
+    fn max_blob_size(&self) -> Option<u64> {
+        if self.split {
+            None
+        } else {
+            self.inner.max_blob_size()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::MemoryDataStore;
+
+    /// Deterministic bytes that don't repeat
+    fn noise(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn store() -> ChunkedDataStore {
+        ChunkedDataStore::new(Arc::new(MemoryDataStore::new()), true)
+    }
+
+    async fn range(store: &ChunkedDataStore, id: &[u8], offset: u64, length: u64) -> Vec<u8> {
+        store
+            .retrieve_range(id, offset, length)
+            .await
+            .unwrap()
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn chunks_stay_within_bounds() {
+        let store = store();
+        let data = noise(3 * MAX_CHUNK, 1);
+
+        assert_eq!(store.cut(&data[..MIN_CHUNK]), None);
+        assert_eq!(store.cut(&vec![0; MAX_CHUNK]), Some(MAX_CHUNK));
+
+        let mut offset = 0;
+        while let Some(length) = store.cut(&data[offset..]) {
+            assert!(length > MIN_CHUNK && length <= MAX_CHUNK);
+            offset += length;
+        }
+        assert!(data.len() - offset < MAX_CHUNK);
+    }
+
+    #[test]
+    fn boundaries_follow_content() {
+        let store = store();
+        let data = noise(3 * MAX_CHUNK, 2);
+        let shifted = [&noise(1000, 3)[..], &data].concat();
+
+        let boundaries = |data: &[u8]| {
+            let mut boundaries = Vec::new();
+            let mut offset = 0;
+            while let Some(length) = store.cut(&data[offset..]) {
+                offset += length;
+                boundaries.push(offset);
+            }
+            boundaries
+        };
+
+        let expected = boundaries(&data)
+            .into_iter()
+            .map(|offset| offset + 1000)
+            .collect::<Vec<_>>();
+        assert!(expected.len() > 3);
+        assert_eq!(boundaries(&shifted), expected);
+    }
+
+    #[test]
+    fn manifests_check_their_size() {
+        let mut manifest = Manifest::default();
+        manifest.chunks.push(Chunk {
+            hash: "00".to_string(),
+            size: 10,
+        });
+        manifest.size = 10;
+
+        let decoded = Manifest::decode(&manifest.encode().unwrap()).unwrap();
+        assert_eq!(decoded.size, 10);
+        assert_eq!(decoded.chunks[0].hash, "00");
+
+        manifest.size = 11;
+        assert!(Manifest::decode(&manifest.encode().unwrap()).is_err());
+        assert!(Manifest::decode(b"not a manifest").is_err());
+    }
+
+    #[tokio::test]
+    async fn big_blobs_are_chunked() {
+        let store = store();
+        let data = noise(3 * MAX_CHUNK, 4);
+
+        let id = store.store(&data).await.unwrap();
+        assert_eq!(id, Blake2s::digest(&data).to_vec());
+        assert_eq!(store.retrieve(&id).await.unwrap(), data);
+        assert_eq!(store.size(&id).await.unwrap(), data.len() as u64);
+
+        let offset = MAX_CHUNK as u64 - 10;
+        let expected = &data[offset as usize..offset as usize + 100];
+        assert_eq!(range(&store, &id, offset, 100).await, expected);
+
+        let references = store.references(&id).await.unwrap();
+        assert_eq!(references[0], manifest_key(&id));
+        assert!(references.len() > 3);
+    }
+
+    #[tokio::test]
+    async fn chunked_blobs_are_read_without_splitting() {
+        let inner = Arc::new(MemoryDataStore::new());
+        let data = noise(3 * MAX_CHUNK, 8);
+        let id = ChunkedDataStore::new(inner.clone(), true)
+            .store(&data)
+            .await
+            .unwrap();
+
+        let store = ChunkedDataStore::new(inner.clone(), false);
+        assert_eq!(store.retrieve(&id).await.unwrap(), data);
+        assert_eq!(store.size(&id).await.unwrap(), data.len() as u64);
+        assert_eq!(range(&store, &id, 10, 100).await, &data[10..110]);
+        assert!(store.references(&id).await.unwrap().len() > 3);
+
+        // New blobs go in whole
+        let whole = noise(3 * MAX_CHUNK, 11);
+        let whole_id = store.store(&whole).await.unwrap();
+        assert_eq!(inner.retrieve(&whole_id).await.unwrap(), whole);
+        assert!(store.references(&whole_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_are_chunked_like_blobs() {
+        for &size in &[MAX_CHUNK, MAX_CHUNK + 1] {
+            let (whole, streamed) = (store(), store());
+            let data = noise(size, 7);
+            let pieces = data
+                .chunks(10_000)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect::<Vec<_>>();
+
+            let id = whole.store(&data).await.unwrap();
+            let streamed_id = streamed.store_stream(Box::pin(stream::iter(pieces))).await;
+            assert_eq!(streamed_id.unwrap(), id);
+
+            let references = whole.references(&id).await.unwrap();
+            assert_eq!(references, streamed.references(&id).await.unwrap());
+            assert_eq!(references.is_empty(), size == MAX_CHUNK);
+        }
+    }
+
+    #[tokio::test]
+    async fn edited_blobs_share_chunks() {
+        let store = store();
+        let data = noise(3 * MAX_CHUNK, 5);
+        let edited = [&b"prefix"[..], &data].concat();
+
+        let first = store.references(&store.store(&data).await.unwrap()).await;
+        let second = store.references(&store.store(&edited).await.unwrap()).await;
+        let (first, second) = (first.unwrap(), second.unwrap());
+
+        let shared = second.iter().filter(|hash| first.contains(hash)).count();
+        assert!(shared >= second.len() - 3);
+    }
+
+    #[tokio::test]
+    async fn blobs_cannot_pose_as_manifests() {
+        let store = store();
+        let victim = store.store(&noise(2 * MAX_CHUNK, 6)).await.unwrap();
+
+        // A manifest listing someone else's chunks, uploaded as a blob
+        let mut manifest = Manifest::default();
+        for hash in store.references(&victim).await.unwrap().iter().skip(1) {
+            let size = store.size(hash).await.unwrap();
+            manifest.chunks.push(Chunk {
+                hash: hex::encode(hash),
+                size,
+            });
+            manifest.size += size;
+        }
+        let forged = manifest.encode().unwrap();
+
+        let id = store.store(&forged).await.unwrap();
+        assert_eq!(store.retrieve(&id).await.unwrap(), forged);
+        assert_eq!(store.size(&id).await.unwrap(), forged.len() as u64);
+        assert!(store.references(&id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,549 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{
+    check_duplicate, slice_stream, ByteStream, DataStore, DataStoreError, PendingId, Result, Sample,
+};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::channel::oneshot;
+use futures::{future, stream, StreamExt, TryStreamExt};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf::{self as aead, Key, Nonce};
+use std::sync::Arc;
+
+const MAGIC: &[u8] = b"reactrix-sealed\0";
+const HEADER_SIZE: usize = MAGIC.len() + aead::NONCEBYTES;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const SEALED_SEGMENT_SIZE: usize = SEGMENT_SIZE + aead::TAGBYTES;
+/// An empty segment after the last one
+const TRAILER_SIZE: usize = aead::TAGBYTES;
+
+/// Where to find the pieces of an encrypted blob
+struct Sealed {
+    nonce: Nonce,
+    size: u64,
+    segments: u64,
+}
+
+impl Sealed {
+    /// Tell the layout from the size of what follows the header
+    fn new(nonce: Nonce, body: u64) -> Result<Self> {
+        let truncated = || DataStoreError::Database("Truncated blob".to_string());
+        if body < TRAILER_SIZE as u64 {
+            return Err(truncated());
+        }
+
+        let sealed = body - TRAILER_SIZE as u64;
+        let segments = sealed.div_ceil(SEALED_SEGMENT_SIZE as u64);
+        // Only the trailer is ever empty
+        let last = sealed - segments.saturating_sub(1) * SEALED_SEGMENT_SIZE as u64;
+        if segments > 0 && last <= aead::TAGBYTES as u64 {
+            return Err(truncated());
+        }
+
+        Ok(Self {
+            nonce,
+            size: sealed - segments * aead::TAGBYTES as u64,
+            segments,
+        })
+    }
+
+    /// Where the trailer starts, counted from the end of the header
+    fn trailer(&self) -> u64 {
+        self.size + self.segments * aead::TAGBYTES as u64
+    }
+}
+
+/// Each segment gets the nonce of the blob with its index mixed in
+fn segment_nonce(nonce: &Nonce, index: u64) -> Nonce {
+    let mut bytes = nonce.0;
+    for (byte, i) in bytes[aead::NONCEBYTES - 8..]
+        .iter_mut()
+        .zip(&index.to_le_bytes())
+    {
+        *byte ^= i;
+    }
+    Nonce(bytes)
+}
+
+/// Binds a segment to its place. The trailer also takes the ID of the blob,
+/// so that a blob can neither be cut off nor pass for another one.
+fn associated_data(index: u64, id: Option<&[u8]>) -> Vec<u8> {
+    let mut data = index.to_le_bytes().to_vec();
+    if let Some(id) = id {
+        data.extend_from_slice(id);
+    }
+    data
+}
+
+fn seal_segment(key: &Key, nonce: &Nonce, index: u64, id: Option<&[u8]>, data: &[u8]) -> Vec<u8> {
+    let associated = associated_data(index, id);
+    aead::seal(data, Some(&associated), &segment_nonce(nonce, index), key)
+}
+
+fn open_segment(key: &Key, nonce: &Nonce, index: u64, sealed: &[u8]) -> Result<Bytes> {
+    let data = associated_data(index, None);
+    match aead::open(sealed, Some(&data), &segment_nonce(nonce, index), key) {
+        Ok(segment) => Ok(Bytes::from(segment)),
+        Err(()) => Err(DataStoreError::Database(format!(
+            "Couldn't decrypt segment {}",
+            index
+        ))),
+    }
+}
+
+fn open_trailer(key: &Key, sealed: &Sealed, id: &[u8], trailer: &[u8]) -> Result<()> {
+    let data = associated_data(sealed.segments, Some(id));
+    let nonce = segment_nonce(&sealed.nonce, sealed.segments);
+    match aead::open(trailer, Some(&data), &nonce, key) {
+        Ok(_) => Ok(()),
+        Err(()) => Err(DataStoreError::Database(format!(
+            "Blob {} was sealed as another one",
+            hex::encode(id)
+        ))),
+    }
+}
+
+/// Decrypt the segments from `first` up to `end` as they come in
+fn open_stream(key: Key, sealed: &Sealed, data: ByteStream, first: u64, end: u64) -> ByteStream {
+    let nonce = sealed.nonce;
+
+    Box::pin(stream::try_unfold(
+        (data, BytesMut::new(), first),
+        move |(mut data, mut buffer, index)| {
+            let key = key.clone();
+            async move {
+                if index >= end {
+                    return Ok(None);
+                }
+
+                while buffer.len() < SEALED_SEGMENT_SIZE {
+                    match data.try_next().await? {
+                        Some(chunk) => buffer.extend_from_slice(&chunk),
+                        None => break,
+                    }
+                }
+
+                let sealed = buffer.split_to(SEALED_SEGMENT_SIZE.min(buffer.len()));
+                let segment = open_segment(&key, &nonce, index, &sealed)?;
+                Ok::<_, DataStoreError>(Some((segment, (data, buffer, index + 1))))
+            }
+        },
+    ))
+}
+
+/// Encrypt segments as they come in and close them with the trailer once
+/// the ID is known, which is then passed on
+fn seal_stream(key: Key, data: ByteStream, id: PendingId) -> (ByteStream, PendingId) {
+    let nonce = aead::gen_nonce();
+    let (known, pending) = oneshot::channel();
+    let header = Bytes::from([MAGIC, &nonce.0[..]].concat());
+
+    let segments = stream::try_unfold(
+        (data, BytesMut::new(), 0, false, Some((id, known))),
+        move |(mut data, mut buffer, index, mut end, ids)| {
+            let key = key.clone();
+            async move {
+                let (id, known) = match ids {
+                    Some(ids) => ids,
+                    None => return Ok(None),
+                };
+
+                while !end && buffer.len() < SEGMENT_SIZE {
+                    match data.try_next().await? {
+                        Some(chunk) => buffer.extend_from_slice(&chunk),
+                        None => end = true,
+                    }
+                }
+
+                if !buffer.is_empty() {
+                    let segment = buffer.split_to(SEGMENT_SIZE.min(buffer.len()));
+                    let sealed = seal_segment(&key, &nonce, index, None, &segment);
+                    let state = (data, buffer, index + 1, end, Some((id, known)));
+                    return Ok(Some((Bytes::from(sealed), state)));
+                }
+
+                let id = id.await?;
+                let trailer = seal_segment(&key, &nonce, index, Some(&id), &[]);
+                // Whoever stopped listening doesn't need it anymore
+                let _ = known.send(id);
+                let state = (data, buffer, index, end, None);
+                Ok::<_, DataStoreError>(Some((Bytes::from(trailer), state)))
+            }
+        },
+    );
+
+    let sealed = stream::once(future::ready(Ok(header))).chain(segments);
+    (Box::pin(sealed), pending)
+}
+
+/// Encrypts blobs on their way to the underlying store with
+/// XChaCha20-Poly1305, in segments so that ranges can be read on their own.
+/// Blobs keep the hash of their plaintext, so duplicates are still found.
+/// Those stored in plaintext before are passed through as they are, unless
+/// `allow_plaintext` is off; once none are left, that keeps anyone who can
+/// write to the underlying store from slipping in blobs that way.
+pub struct EncryptedDataStore {
+    inner: Arc<dyn DataStore>,
+    key: Key,
+    allow_plaintext: bool,
+}
+
+impl EncryptedDataStore {
+    pub fn new(inner: Arc<dyn DataStore>, key: &[u8], allow_plaintext: bool) -> Result<Self> {
+        sodiumoxide::init()
+            .map_err(|_| DataStoreError::Database("Couldn't initialize libsodium".to_string()))?;
+
+        match Key::from_slice(key) {
+            Some(key) => Ok(Self {
+                inner,
+                key,
+                allow_plaintext,
+            }),
+            None => Err(DataStoreError::Database(format!(
+                "Encryption key must be {} bytes long",
+                aead::KEYBYTES
+            ))),
+        }
+    }
+
+    fn seal(&self, id: &[u8], data: &[u8]) -> Vec<u8> {
+        let nonce = aead::gen_nonce();
+        let segments = data.chunks(SEGMENT_SIZE);
+
+        let mut sealed =
+            Vec::with_capacity(HEADER_SIZE + data.len() + (segments.len() + 1) * aead::TAGBYTES);
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce.0);
+
+        let mut index = 0;
+        for segment in segments {
+            sealed.extend(seal_segment(&self.key, &nonce, index, None, segment));
+            index += 1;
+        }
+        sealed.extend(seal_segment(&self.key, &nonce, index, Some(id), &[]));
+
+        sealed
+    }
+
+    fn open(&self, id: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(&data[MAGIC.len()..HEADER_SIZE])
+            .ok_or_else(|| DataStoreError::Database("Truncated header".to_string()))?;
+        let body = &data[HEADER_SIZE..];
+        let sealed = Sealed::new(nonce, body.len() as u64)?;
+        let trailer = sealed.trailer() as usize;
+        open_trailer(&self.key, &sealed, id, &body[trailer..])?;
+
+        let mut opened = Vec::with_capacity(sealed.size as usize);
+        for (index, segment) in (0..).zip(body[..trailer].chunks(SEALED_SEGMENT_SIZE)) {
+            opened.extend_from_slice(&open_segment(&self.key, &nonce, index, segment)?);
+        }
+
+        Ok(opened)
+    }
+
+    /// Whether a blob in plaintext may be passed through
+    fn plaintext(&self, id: &[u8]) -> Result<()> {
+        if self.allow_plaintext {
+            Ok(())
+        } else {
+            Err(DataStoreError::Database(format!(
+                "Blob {} isn't encrypted",
+                hex::encode(id)
+            )))
+        }
+    }
+
+    /// How an encrypted blob is laid out, or `None` for one in plaintext
+    async fn sealed(&self, id: &[u8]) -> Result<Option<Sealed>> {
+        let header = self.read(id, 0, HEADER_SIZE).await?;
+        if header.len() < HEADER_SIZE || !header.starts_with(MAGIC) {
+            self.plaintext(id)?;
+            return Ok(None);
+        }
+
+        let nonce = Nonce::from_slice(&header[MAGIC.len()..])
+            .ok_or_else(|| DataStoreError::Database("Truncated header".to_string()))?;
+        let body = self.inner.size(id).await? - HEADER_SIZE as u64;
+        let sealed = Sealed::new(nonce, body)?;
+
+        let trailer = self
+            .read(id, HEADER_SIZE as u64 + sealed.trailer(), TRAILER_SIZE)
+            .await?;
+        open_trailer(&self.key, &sealed, id, &trailer)?;
+
+        Ok(Some(sealed))
+    }
+
+    async fn read(&self, id: &[u8], offset: u64, length: usize) -> Result<Vec<u8>> {
+        self.inner
+            .retrieve_range(id, offset, length as u64)
+            .await?
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+    }
+}
+
+#[async_trait]
+impl DataStore for EncryptedDataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = Blake2s::digest(data);
+
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        let data = self.inner.retrieve(id).await?;
+
+        if data.len() >= HEADER_SIZE && data.starts_with(MAGIC) {
+            self.open(id, &data)
+        } else {
+            self.plaintext(id)?;
+            Ok(data)
+        }
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        self.inner.store_as(id, &self.seal(id, data)).await
+    }
+
+    async fn store_stream_as(&self, data: ByteStream, id: PendingId) -> Result<bool> {
+        let (sealed, id) = seal_stream(self.key.clone(), data, id);
+        self.inner.store_stream_as(sealed, id).await
+    }
+
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        let sealed = match self.sealed(id).await? {
+            Some(sealed) => sealed,
+            None => return self.inner.retrieve_stream(id).await,
+        };
+
+        let data = self
+            .inner
+            .retrieve_range(id, HEADER_SIZE as u64, sealed.trailer())
+            .await?;
+        Ok(open_stream(self.key.clone(), &sealed, data, 0, sealed.segments))
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        match self.sealed(id).await? {
+            Some(sealed) => Ok(sealed.size),
+            None => self.inner.size(id).await,
+        }
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        let sealed = match self.sealed(id).await? {
+            Some(sealed) => sealed,
+            None => return self.inner.retrieve_range(id, offset, length).await,
+        };
+
+        // Only read the segments overlapping the range
+        let end = offset.saturating_add(length).min(sealed.size);
+        if offset >= end {
+            return Ok(Box::pin(stream::empty()));
+        }
+
+        let first = offset / SEGMENT_SIZE as u64;
+        let last = (end - 1) / SEGMENT_SIZE as u64 + 1;
+        let start = first * SEALED_SEGMENT_SIZE as u64;
+        // The last segment may be short, and the trailer must not be taken
+        // for a part of it
+        let stop = (last * SEALED_SEGMENT_SIZE as u64).min(sealed.trailer());
+        let data = self
+            .inner
+            .retrieve_range(id, HEADER_SIZE as u64 + start, stop - start)
+            .await?;
+
+        let segments = open_stream(self.key.clone(), &sealed, data, first, last);
+        Ok(slice_stream(segments, offset - first * SEGMENT_SIZE as u64, length))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        self.inner.list(before).await
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        self.inner.remove(id, before).await
+    }
+
+    async fn remove_uploads(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.inner.remove_uploads(before).await
+    }
+
+    async fn references(&self, id: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner.references(id).await
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        self.inner.max_blob_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::MemoryDataStore;
+
+    const SIZES: [usize; 5] = [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 5];
+
+    fn store() -> (Arc<MemoryDataStore>, EncryptedDataStore) {
+        let inner = Arc::new(MemoryDataStore::new());
+        let store = EncryptedDataStore::new(inner.clone(), &[7; aead::KEYBYTES], true).unwrap();
+        (inner, store)
+    }
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Pieces that don't line up with segments
+    fn pieces(data: &[u8]) -> ByteStream {
+        let pieces = data
+            .chunks(10_000)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect::<Vec<_>>();
+        Box::pin(stream::iter(pieces))
+    }
+
+    async fn concat(data: ByteStream) -> Vec<u8> {
+        data.map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap()
+    }
+
+    /// Put something else under `id` in the underlying store
+    async fn replace(inner: &MemoryDataStore, id: &[u8], data: &[u8]) {
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        assert!(inner.remove(id, later).await.unwrap());
+        assert!(inner.store_as(id, data).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn blobs_are_sealed() {
+        let (inner, store) = store();
+
+        for &size in &SIZES {
+            let data = data(size);
+            let id = store.store(&data).await.unwrap();
+            assert_eq!(id, Blake2s::digest(&data).to_vec());
+
+            let sealed = inner.retrieve(&id).await.unwrap();
+            let tags = size.div_ceil(SEGMENT_SIZE) + 1;
+            assert_eq!(sealed.len(), HEADER_SIZE + size + tags * aead::TAGBYTES);
+            // Shorter ones may well start with the same bytes by chance
+            assert!(size < 16 || !sealed[HEADER_SIZE..].starts_with(&data[..16]));
+
+            assert_eq!(store.retrieve(&id).await.unwrap(), data);
+            assert_eq!(store.size(&id).await.unwrap(), size as u64);
+            let stream = store.retrieve_stream(&id).await.unwrap();
+            assert_eq!(concat(stream).await, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_are_sealed_alike() {
+        let (inner, store) = store();
+        let (whole_inner, whole) = self::store();
+
+        for &size in &SIZES {
+            let data = data(size);
+            let id = store.store_stream(pieces(&data)).await.unwrap();
+            assert_eq!(id, whole.store(&data).await.unwrap());
+            assert_eq!(
+                inner.size(&id).await.unwrap(),
+                whole_inner.size(&id).await.unwrap()
+            );
+            assert_eq!(store.retrieve(&id).await.unwrap(), data);
+
+            // Stored again, it's found to be the same
+            assert_eq!(store.store_stream(pieces(&data)).await.unwrap(), id);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_only_need_their_segments() {
+        let (_, store) = store();
+        let data = data(3 * SEGMENT_SIZE + 5);
+        let id = store.store(&data).await.unwrap();
+
+        let ranges = [
+            (0, 10),
+            (SEGMENT_SIZE - 3, 6),
+            (SEGMENT_SIZE, SEGMENT_SIZE),
+            (3 * SEGMENT_SIZE, 100),
+            (data.len(), 10),
+        ];
+        for &(offset, length) in &ranges {
+            let range = store
+                .retrieve_range(&id, offset as u64, length as u64)
+                .await
+                .unwrap();
+            let end = data.len().min(offset + length);
+            assert_eq!(concat(range).await, &data[offset..end]);
+        }
+    }
+
+    #[tokio::test]
+    async fn blobs_cannot_be_swapped() {
+        let (inner, store) = store();
+        let first = store.store(&data(SEGMENT_SIZE + 1)).await.unwrap();
+        let second = store.store(&data(SEGMENT_SIZE + 2)).await.unwrap();
+
+        replace(&inner, &second, &inner.retrieve(&first).await.unwrap()).await;
+        assert!(store.retrieve(&second).await.is_err());
+        assert!(store.size(&second).await.is_err());
+        assert!(store.retrieve_range(&second, 0, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn blobs_cannot_be_cut_off() {
+        let (inner, store) = store();
+        let id = store.store(&data(SEGMENT_SIZE + 1)).await.unwrap();
+        let sealed = inner.retrieve(&id).await.unwrap();
+
+        // Without the trailer, and without the last segment as well
+        for &cut in &[TRAILER_SIZE, TRAILER_SIZE + 1 + aead::TAGBYTES] {
+            replace(&inner, &id, &sealed[..sealed.len() - cut]).await;
+            assert!(store.retrieve(&id).await.is_err());
+            assert!(store.retrieve_stream(&id).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_passed_through_on_request() {
+        // As it is by default, for blobs stored before encryption
+        let (inner, store) = store();
+        let id = inner.store(b"stored before").await.unwrap();
+        assert_eq!(store.retrieve(&id).await.unwrap(), b"stored before");
+        assert_eq!(concat(store.retrieve_stream(&id).await.unwrap()).await, b"stored before");
+        assert_eq!(store.size(&id).await.unwrap(), 13);
+
+        let store = EncryptedDataStore::new(inner, &[7; aead::KEYBYTES], false).unwrap();
+        assert!(store.retrieve(&id).await.is_err());
+        assert!(store.retrieve_stream(&id).await.is_err());
+        assert!(store.size(&id).await.is_err());
+    }
+}
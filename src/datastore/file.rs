@@ -0,0 +1,350 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{
+    check_duplicate, upload_name, ByteStream, DataStore, DataStoreError, PendingId, Result, Sample,
+};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use futures::{stream, TryStreamExt};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::task;
+use url::Url;
+
+const TEMPORARY: &str = "tmp";
+const READ_SIZE: usize = 64 * 1024;
+
+/// Keeps each blob in a file named after its hex hash, in a directory tree
+/// of the first two bytes (e.g. `ab/cd/abcd…`) so no directory grows too big
+pub struct FileDataStore(PathBuf);
+
+/// Make a blob young again for the collector, without writing to it
+fn touch(path: &Path) -> io::Result<()> {
+    filetime::set_file_mtime(path, FileTime::from_system_time(SystemTime::now()))
+}
+
+/// Link a finished blob into place, atomically for readers. Unlike a
+/// rename, this fails rather than replace a blob that is there already,
+/// but it makes that one young again for the collector.
+fn commit(temporary: &Path, path: &Path) -> io::Result<bool> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    loop {
+        match fs::hard_link(temporary, path) {
+            Ok(()) => break,
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => match touch(path) {
+                Ok(()) => return Ok(false),
+                // Removed in between, so link it again
+                Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(true)
+}
+
+/// Write to a temporary file first so readers never see a partial blob
+fn write(temporary: &Path, path: &Path, data: &[u8]) -> io::Result<bool> {
+    let result = File::create(temporary)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| commit(temporary, path));
+
+    let _ = fs::remove_file(temporary);
+    result
+}
+
+/// Copy a stream to a temporary file
+async fn stage(temporary: &Path, mut data: ByteStream) -> Result<()> {
+    let mut file = tokio::fs::File::create(temporary).await?;
+
+    while let Some(chunk) = data.try_next().await? {
+        file.write_all(&chunk).await?;
+    }
+
+    Ok(file.sync_all().await?)
+}
+
+/// Move a blob aside and only remove it if it's still old then. Storing it
+/// again in the meantime either touched it before, so it goes back in place,
+/// or finds it gone and links it anew. Only a touch that finds it before the
+/// move and updates it after the check still loses it.
+fn remove(path: &Path, aside: &Path, before: SystemTime) -> io::Result<bool> {
+    match fs::rename(path, aside) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    }
+
+    let old = fs::metadata(aside)?.modified()? < before;
+    if !old {
+        match fs::hard_link(aside, path) {
+            Ok(()) => {}
+            // Stored again after it was moved aside
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    fs::remove_file(aside)?;
+    Ok(old)
+}
+
+/// Temporary files older than `before` of uploads that never finished
+fn remove_uploads(dir: &Path, before: SystemTime) -> io::Result<usize> {
+    let mut removed = 0;
+
+    for upload in fs::read_dir(dir)? {
+        let upload = upload?;
+        if upload.metadata()?.modified()? < before {
+            match fs::remove_file(upload.path()) {
+                Ok(()) => removed += 1,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Blobs in the two levels of directories below `root` modified before `before`
+fn list(root: &Path, before: SystemTime) -> io::Result<Vec<Vec<u8>>> {
+    let mut hashes = Vec::new();
+
+    for first in fs::read_dir(root)? {
+        let first = first?;
+        if first.file_name() == TEMPORARY || !first.file_type()?.is_dir() {
+            continue;
+        }
+
+        for second in fs::read_dir(first.path())? {
+            for blob in fs::read_dir(second?.path())? {
+                let blob = blob?;
+                if blob.metadata()?.modified()? >= before {
+                    continue;
+                }
+
+                if let Ok(hash) = hex::decode(blob.file_name().to_string_lossy().as_bytes()) {
+                    hashes.push(hash);
+                }
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+fn read_chunks<R>(file: R) -> ByteStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    Box::pin(stream::try_unfold(file, |mut file| async move {
+        let mut buffer = vec![0; READ_SIZE];
+        let n = file.read(&mut buffer).await.map_err(DataStoreError::from)?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        buffer.truncate(n);
+        Ok::<_, DataStoreError>(Some((Bytes::from(buffer), file)))
+    }))
+}
+
+impl FileDataStore {
+    pub fn new(root: PathBuf) -> Result<Self> {
+        fs::create_dir_all(root.join(TEMPORARY))?;
+        Ok(Self(root))
+    }
+
+    /// Open the directory of a `file://` URL
+    pub fn open(url: &Url) -> Result<Self> {
+        match url.to_file_path() {
+            Ok(root) => Self::new(root),
+            Err(_) => Err(DataStoreError::Database(format!("Invalid path in {}", url))),
+        }
+    }
+
+    fn path(&self, hash: &[u8]) -> Result<PathBuf> {
+        if hash.len() < 2 {
+            return Err(DataStoreError::NoRecord);
+        }
+
+        let name = hex::encode(hash);
+        Ok(self.0.join(&name[..2]).join(&name[2..4]).join(name))
+    }
+
+    /// Concurrent uploads of the same blob each get their own temporary
+    /// file; whichever is linked first wins
+    fn temporary(&self) -> PathBuf {
+        self.0.join(TEMPORARY).join(upload_name())
+    }
+
+    async fn open_blob(&self, id: &[u8]) -> Result<tokio::fs::File> {
+        match tokio::fs::File::open(self.path(id)?).await {
+            Ok(file) => Ok(file),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Err(DataStoreError::NoRecord),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store_staged(
+        &self,
+        temporary: &Path,
+        data: ByteStream,
+        id: PendingId,
+    ) -> Result<bool> {
+        stage(temporary, data).await?;
+        let path = self.path(&id.await?)?;
+        let staged = temporary.to_path_buf();
+
+        Ok(task::spawn_blocking(move || commit(&staged, &path)).await??)
+    }
+}
+
+#[async_trait]
+impl DataStore for FileDataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = Blake2s::digest(data);
+
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        let path = self.path(id)?;
+        let temporary = self.temporary();
+        let data = data.to_vec();
+
+        Ok(task::spawn_blocking(move || write(&temporary, &path, &data)).await??)
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        let path = self.path(id)?;
+
+        match task::spawn_blocking(move || fs::read(path)).await? {
+            Ok(data) => Ok(data),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Err(DataStoreError::NoRecord),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store_stream_as(&self, data: ByteStream, id: PendingId) -> Result<bool> {
+        let temporary = self.temporary();
+        let result = self.store_staged(&temporary, data, id).await;
+
+        // Once committed, the blob has a link of its own
+        let _ = tokio::fs::remove_file(&temporary).await;
+        result
+    }
+
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        Ok(read_chunks(self.open_blob(id).await?))
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        Ok(self.open_blob(id).await?.metadata().await?.len())
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        let mut file = self.open_blob(id).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(read_chunks(file.take(length)))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        let root = self.0.clone();
+        Ok(task::spawn_blocking(move || list(&root, before.into())).await??)
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        let path = self.path(id)?;
+        let aside = self.temporary();
+        Ok(task::spawn_blocking(move || remove(&path, &aside, before.into())).await??)
+    }
+
+    async fn remove_uploads(&self, before: DateTime<Utc>) -> Result<usize> {
+        let dir = self.0.join(TEMPORARY);
+        Ok(task::spawn_blocking(move || remove_uploads(&dir, before.into())).await??)
+    }
+}
+
+impl From<io::Error> for DataStoreError {
+    fn from(error: io::Error) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::process;
+
+    fn scratch(name: &str) -> FileDataStore {
+        let dir = std::env::temp_dir().join(format!("reactrix-blobs-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        FileDataStore::new(dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn blobs_stored_again_stay() {
+        let store = scratch("remove");
+        let hash = store.store(b"blob").await.unwrap();
+        let before = Utc::now();
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        store.store(b"blob").await.unwrap();
+        assert!(!store.remove(&hash, before).await.unwrap());
+        assert_eq!(store.retrieve(&hash).await.unwrap(), b"blob");
+
+        let later = Utc::now() + Duration::seconds(1);
+        assert!(store.remove(&hash, later).await.unwrap());
+        assert!(!store.remove(&hash, later).await.unwrap());
+        assert!(store.retrieve(&hash).await.is_err());
+        fs::remove_dir_all(&store.0).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unfinished_uploads_are_removed() {
+        let store = scratch("uploads");
+        fs::write(store.temporary(), b"left behind").unwrap();
+        let earlier = Utc::now() - Duration::hours(1);
+        let later = Utc::now() + Duration::seconds(1);
+
+        assert_eq!(store.remove_uploads(earlier).await.unwrap(), 0);
+        assert_eq!(store.remove_uploads(later).await.unwrap(), 1);
+        assert_eq!(fs::read_dir(store.0.join(TEMPORARY)).unwrap().count(), 0);
+        fs::remove_dir_all(&store.0).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{check_duplicate, ByteStream, DataStore, DataStoreError, Result, Sample};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{future, stream};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+struct Blob {
+    data: Vec<u8>,
+    created: DateTime<Utc>,
+}
+
+/// Keeps everything in process memory, for tests and local development
+#[derive(Default)]
+pub struct MemoryDataStore(Mutex<HashMap<Vec<u8>, Blob>>);
+
+impl MemoryDataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn blobs(&self) -> Result<MutexGuard<'_, HashMap<Vec<u8>, Blob>>> {
+        self.0
+            .lock()
+            .map_err(|e| DataStoreError::Database(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl DataStore for MemoryDataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = Blake2s::digest(data);
+
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        let blob = Blob {
+            data: data.to_vec(),
+            created: Utc::now(),
+        };
+
+        match self.blobs()?.entry(id.to_vec()) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().created = blob.created;
+                Ok(false)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(blob);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        self.blobs()?
+            .get(id)
+            .map(|blob| blob.data.clone())
+            .ok_or(DataStoreError::NoRecord)
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        match self.blobs()?.get(id) {
+            Some(blob) => Ok(blob.data.len() as u64),
+            None => Err(DataStoreError::NoRecord),
+        }
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        let data = match self.blobs()?.get(id) {
+            Some(Blob { data, .. }) => {
+                let start = (offset as usize).min(data.len());
+                let end = start + (length as usize).min(data.len() - start);
+                Bytes::copy_from_slice(&data[start..end])
+            }
+            None => return Err(DataStoreError::NoRecord),
+        };
+
+        Ok(Box::pin(stream::once(future::ready(Ok(data)))))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .blobs()?
+            .iter()
+            .filter(|(_, blob)| blob.created < before)
+            .map(|(hash, _)| hash.clone())
+            .collect())
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        let mut blobs = self.blobs()?;
+        match blobs.get(id) {
+            Some(blob) if blob.created < before => {
+                blobs.remove(id);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
@@ -14,55 +14,507 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::datastore::{entry_exists, DataStore, DataStoreError, Result};
+use crate::datastore::{
+    check_duplicate, slice_stream, ByteStream, DataStore, DataStoreError, PendingId, Result, Sample,
+};
+use crate::eventstore::is_duplicate;
 
+use async_trait::async_trait;
 use blake2::{Blake2s, Digest};
-use bson::doc;
+use bson::oid::{self, ObjectId};
 use bson::ordered::ValueAccessError;
 use bson::spec::BinarySubtype;
-use futures::executor::block_on;
+use bson::{doc, Document};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use log::error;
 use mongodb::error::Error as MongoError;
+use mongodb::options::{AggregateOptions, FindOneOptions, FindOptions, UpdateOptions};
 use mongodb::Database;
 
+/// Leaves room for the rest of the document below the 16 MB limit
+const INLINE_LIMIT: usize = 15 * 1024 * 1024;
+const CHUNK_SIZE: usize = 255 * 1024;
+const CHUNKS_PER_INSERT: usize = 32;
+const BATCH_SIZE: usize = CHUNK_SIZE * CHUNKS_PER_INSERT;
+const FILES: &str = "fs.files";
+const CHUNKS: &str = "fs.chunks";
+
+/// Keeps small blobs as single documents in the `data` collection and
+/// anything bigger in GridFS, as a file in `fs.files` named after its hash
+/// with its chunks in `fs.chunks`. The file document goes in last, so that
+/// readers only ever see blobs with all of their chunks in place; this
+/// relies on a unique index on `fs.files.filename`.
 pub struct MongoDataStore(Database);
 
 impl MongoDataStore {
     pub fn new(database: Database) -> Self {
         Self(database)
     }
+
+    /// Insert a document unless one matches `filter` already. Either way,
+    /// `stamp` is set to now so that the collector sees it as young.
+    async fn insert_new(
+        &self,
+        collection: &str,
+        filter: Document,
+        stamp: &str,
+        fields: Document,
+    ) -> Result<bool> {
+        let options = UpdateOptions::builder().upsert(Some(true)).build();
+        let mut now = Document::new();
+        now.insert(stamp, Utc::now());
+        let update = doc! { "$setOnInsert": fields, "$set": now };
+
+        match self
+            .0
+            .collection(collection)
+            .update_one(filter, update, Some(options))
+            .await
+        {
+            Ok(result) => Ok(result.upserted_id.is_some()),
+            // Concurrent upserts of the same blob may also end up like this
+            Err(ref e) if is_duplicate(e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store_inline(&self, id: &str, data: &[u8]) -> Result<bool> {
+        let fields = doc! {
+            "length": data.len() as i64,
+            "data": (BinarySubtype::Generic, data.to_owned()),
+        };
+
+        self.insert_new("data", doc! { "_id": id }, "created", fields)
+            .await
+    }
+
+    /// Insert `data` as chunks numbered from `n` on and return the next number
+    async fn insert_chunks(&self, files_id: &ObjectId, n: i32, data: &[u8]) -> Result<i32> {
+        let docs = data
+            .chunks(CHUNK_SIZE)
+            .zip(n..)
+            .map(|(chunk, n)| {
+                doc! {
+                    "files_id": files_id.clone(),
+                    "n": n,
+                    "data": (BinarySubtype::Generic, chunk.to_owned()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let next = n + docs.len() as i32;
+        if !docs.is_empty() {
+            self.0.collection(CHUNKS).insert_many(docs, None).await?;
+        }
+
+        Ok(next)
+    }
+
+    /// Name a file after `id` for the chunks stored under `files_id`,
+    /// unless there's one with that name already
+    async fn insert_file(&self, id: &str, files_id: &ObjectId, length: usize) -> Result<bool> {
+        let fields = doc! {
+            "_id": files_id.clone(),
+            "length": length as i64,
+            "chunkSize": CHUNK_SIZE as i32,
+        };
+
+        self.insert_new(FILES, doc! { "filename": id }, "uploadDate", fields)
+            .await
+    }
+
+    async fn store_chunks(&self, id: &str, files_id: &ObjectId, data: &[u8]) -> Result<bool> {
+        let mut n = 0;
+        for batch in data.chunks(BATCH_SIZE) {
+            n = self.insert_chunks(files_id, n, batch).await?;
+        }
+
+        self.insert_file(id, files_id, data.len()).await
+    }
+
+    /// Remove chunks that no file document refers to
+    async fn remove_upload(&self, files_id: &ObjectId) {
+        let chunks = self.0.collection(CHUNKS);
+        let filter = doc! { "files_id": files_id.clone() };
+        if let Err(e) = chunks.delete_many(filter, None).await {
+            error!("Couldn't remove chunks of upload {}: {}", files_id, e);
+        }
+    }
+
+    /// Spill the rest of a stream into chunks and name a file after its
+    /// hash once it's known
+    async fn store_upload(
+        &self,
+        files_id: &ObjectId,
+        mut buffer: Vec<u8>,
+        mut data: ByteStream,
+        id: PendingId,
+    ) -> Result<bool> {
+        let mut n = 0;
+        let mut length = 0;
+
+        loop {
+            while buffer.len() >= BATCH_SIZE {
+                let rest = buffer.split_off(BATCH_SIZE);
+                n = self.insert_chunks(files_id, n, &buffer).await?;
+                length += buffer.len();
+                buffer = rest;
+            }
+
+            match data.try_next().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+
+        self.insert_chunks(files_id, n, &buffer).await?;
+        length += buffer.len();
+
+        let id = hex::encode(id.await?);
+        self.insert_file(&id, files_id, length).await
+    }
+
+    /// Values of `field` that are hashes
+    async fn hashes(
+        &self,
+        collection: &str,
+        field: &str,
+        filter: Document,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut projection = Document::new();
+        projection.insert(field, 1);
+        let options = FindOptions::builder().projection(Some(projection)).build();
+        let mut cursor = self.0.collection(collection).find(filter, Some(options)).await?;
+
+        let mut hashes = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            if let Ok(hash) = hex::decode(doc?.get_str(field)?) {
+                hashes.push(hash);
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    async fn find_file(&self, id: &str) -> Result<Option<Document>> {
+        Ok(self
+            .0
+            .collection(FILES)
+            .find_one(doc! { "filename": id }, None)
+            .await?)
+    }
+
+    /// Chunks of a file from number `first` on. The stream fails rather
+    /// than end early if any of them are missing, the last ones included.
+    async fn chunk_stream(&self, file: &Document, first: i32) -> Result<ByteStream> {
+        let files_id = file.get_object_id("_id")?;
+        let chunk_size = file.get_i32("chunkSize").unwrap_or(CHUNK_SIZE as i32);
+        let remaining = (file.get_i64("length")? - i64::from(first) * i64::from(chunk_size)).max(0);
+
+        let options = FindOptions::builder().sort(Some(doc! { "n": 1 })).build();
+        let cursor = self
+            .0
+            .collection(CHUNKS)
+            .find(
+                doc! { "files_id": files_id.clone(), "n": { "$gte": first } },
+                Some(options),
+            )
+            .await?;
+
+        let id = files_id.to_hex();
+        let state = (cursor, first, 0, false);
+
+        Ok(Box::pin(stream::unfold(
+            state,
+            move |(mut cursor, expected, streamed, failed)| {
+                let id = id.clone();
+                async move {
+                    if failed {
+                        return None;
+                    }
+
+                    let chunk = match cursor.next().await {
+                        Some(chunk) => chunk,
+                        None if streamed != remaining => {
+                            let error = DataStoreError::Database(format!(
+                                "{} is incomplete, chunk {} is missing",
+                                id, expected
+                            ));
+                            return Some((Err(error), (cursor, expected, streamed, true)));
+                        }
+                        None => return None,
+                    };
+
+                    let data = chunk.map_err(DataStoreError::from).and_then(|chunk| {
+                        if chunk.get_i32("n")? != expected {
+                            return Err(DataStoreError::Database(format!(
+                                "Chunk {} of {} is missing",
+                                expected, id
+                            )));
+                        }
+                        Ok(Bytes::from(chunk.get_binary_generic("data")?.to_owned()))
+                    });
+
+                    match data {
+                        Ok(data) => {
+                            let streamed = streamed + data.len() as i64;
+                            Some((Ok(data), (cursor, expected + 1, streamed, false)))
+                        }
+                        Err(e) => Some((Err(e), (cursor, expected, streamed, true))),
+                    }
+                }
+            },
+        )))
+    }
+
+    async fn retrieve_inline(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        match self
+            .0
+            .collection("data")
+            .find_one(doc! { "_id": id }, None)
+            .await
+        {
+            Ok(Some(ref doc)) if doc.contains_key("$err") => {
+                Err(DataStoreError::Database(doc.get_str("$err")?.to_owned()))
+            }
+            Ok(Some(doc)) => Ok(Some(doc.get_binary_generic("data")?.to_owned())),
+            Ok(None) => Ok(None),
+            Err(e) => Err(DataStoreError::Database(e.to_string())),
+        }
+    }
+
+    /// Size of an inline blob without fetching it, except for those stored
+    /// before their length was kept alongside
+    async fn inline_length(&self, id: &str) -> Result<Option<u64>> {
+        let options = FindOneOptions::builder()
+            .projection(Some(doc! { "length": 1 }))
+            .build();
+        let doc = self
+            .0
+            .collection("data")
+            .find_one(doc! { "_id": id }, Some(options))
+            .await?;
+
+        match doc {
+            Some(doc) => match doc.get_i64("length") {
+                Ok(length) => Ok(Some(length as u64)),
+                Err(_) => Ok(self.retrieve_inline(id).await?.map(|d| d.len() as u64)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn retrieve_chunks(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let file = match self.find_file(id).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let length = file.get_i64("length")? as usize;
+        let mut chunks = self.chunk_stream(&file, 0).await?;
+
+        let mut data = Vec::with_capacity(length);
+        while let Some(chunk) = chunks.try_next().await? {
+            data.extend_from_slice(&chunk);
+        }
+
+        if data.len() != length {
+            return Err(DataStoreError::Database(format!("{} is incomplete", id)));
+        }
+
+        Ok(Some(data))
+    }
 }
 
+#[async_trait]
 impl DataStore for MongoDataStore {
-    fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
         let hash = Blake2s::digest(data);
 
-        if entry_exists(self, &hash, data)? {
-            return Ok(hash.to_vec());
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
         }
 
-        let doc =
-            doc! { "_id": hex::encode(hash), "data": (BinarySubtype::Generic, data.to_owned()) };
+        Ok(hash.to_vec())
+    }
 
-        block_on(self.0.collection("data").insert_one(doc, None))?;
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        let id = hex::encode(id);
 
-        Ok(hash.to_vec())
+        if data.len() <= INLINE_LIMIT {
+            return self.store_inline(&id, data).await;
+        }
+
+        let files_id = ObjectId::new()?;
+        let result = self.store_chunks(&id, &files_id, data).await;
+
+        match result {
+            Ok(true) => {}
+            // Don't leave orphaned chunks behind
+            _ => self.remove_upload(&files_id).await,
+        }
+        result
     }
 
-    fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
         let hash = hex::encode(id);
 
-        match block_on(
-            self.0
-                .collection("data")
-                .find_one(doc! { "_id": hash }, None),
-        ) {
-            Ok(Some(ref doc)) if doc.contains_key(&"$err") => {
-                Err(DataStoreError::Database(doc.get_str(&"$err")?.to_owned()))
+        match self.retrieve_inline(&hash).await? {
+            Some(data) => Ok(data),
+            None => self
+                .retrieve_chunks(&hash)
+                .await?
+                .ok_or(DataStoreError::NoRecord),
+        }
+    }
+
+    async fn store_stream_as(&self, mut data: ByteStream, id: PendingId) -> Result<bool> {
+        let mut buffer = Vec::new();
+
+        // Whatever fits goes inline, just as with `store`
+        while buffer.len() <= INLINE_LIMIT {
+            match data.try_next().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => return self.store_as(&id.await?, &buffer).await,
             }
-            Ok(Some(doc)) => Ok(doc.get_binary_generic("data")?.to_owned()),
-            Ok(None) => Err(DataStoreError::NoRecord),
-            Err(e) => Err(DataStoreError::Database(e.to_string())),
         }
+
+        let files_id = ObjectId::new()?;
+        let result = self.store_upload(&files_id, buffer, data, id).await;
+
+        match result {
+            Ok(true) => {}
+            // Unless a file claimed them, the chunks are of no use anymore
+            _ => self.remove_upload(&files_id).await,
+        }
+        result
+    }
+
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        let hash = hex::encode(id);
+
+        if let Some(data) = self.retrieve_inline(&hash).await? {
+            return Ok(Box::pin(stream::once(future::ready(Ok(Bytes::from(data))))));
+        }
+
+        match self.find_file(&hash).await? {
+            Some(file) => self.chunk_stream(&file, 0).await,
+            None => Err(DataStoreError::NoRecord),
+        }
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        let hash = hex::encode(id);
+
+        if let Some(length) = self.inline_length(&hash).await? {
+            return Ok(length);
+        }
+
+        match self.find_file(&hash).await? {
+            Some(file) => Ok(file.get_i64("length")? as u64),
+            None => Err(DataStoreError::NoRecord),
+        }
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        let hash = hex::encode(id);
+
+        if let Some(data) = self.retrieve_inline(&hash).await? {
+            let start = (offset as usize).min(data.len());
+            let end = start + (length as usize).min(data.len() - start);
+            let data = Bytes::copy_from_slice(&data[start..end]);
+            return Ok(Box::pin(stream::once(future::ready(Ok(data)))));
+        }
+
+        let file = match self.find_file(&hash).await? {
+            Some(file) => file,
+            None => return Err(DataStoreError::NoRecord),
+        };
+
+        // Only fetch chunks from the one containing the offset on
+        let first = offset / CHUNK_SIZE as u64;
+        let chunks = self.chunk_stream(&file, first as i32).await?;
+        Ok(slice_stream(chunks, offset - first * CHUNK_SIZE as u64, length))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        let old = doc! {
+            "$or": [
+                { "created": { "$lt": before } },
+                { "created": { "$exists": false } },
+            ]
+        };
+
+        let mut hashes = self.hashes("data", "_id", old).await?;
+        let old = doc! { "uploadDate": { "$lt": before } };
+        hashes.extend(self.hashes(FILES, "filename", old).await?);
+        Ok(hashes)
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        let id = hex::encode(id);
+
+        let old = doc! {
+            "_id": id.as_str(),
+            "$or": [
+                { "created": { "$lt": before } },
+                { "created": { "$exists": false } },
+            ]
+        };
+        let inline = self.0.collection("data").delete_one(old, None).await?;
+
+        let old = doc! { "filename": id.as_str(), "uploadDate": { "$lt": before } };
+        let file = self
+            .0
+            .collection(FILES)
+            .find_one_and_delete(old, None)
+            .await?;
+        if let Some(ref file) = file {
+            let chunks = doc! { "files_id": file.get_object_id("_id")?.clone() };
+            self.0.collection(CHUNKS).delete_many(chunks, None).await?;
+        }
+
+        Ok(inline.deleted_count > 0 || file.is_some())
+    }
+
+    /// Chunks that no file claimed, of uploads started before `before`
+    /// according to the time in their ObjectId. The server finds them, so
+    /// that neither the files nor the uploads have to fit into memory here.
+    async fn remove_uploads(&self, before: DateTime<Utc>) -> Result<usize> {
+        let started = ObjectId::with_timestamp(before.timestamp() as u32);
+        let pipeline = vec![
+            doc! { "$match": { "files_id": { "$lt": started } } },
+            doc! { "$group": { "_id": "$files_id" } },
+            doc! {
+                "$lookup": {
+                    "from": FILES,
+                    "localField": "_id",
+                    "foreignField": "_id",
+                    "as": "files",
+                }
+            },
+            doc! { "$match": { "files": { "$size": 0 } } },
+            doc! { "$project": { "_id": 1 } },
+        ];
+        let options = AggregateOptions::builder()
+            .allow_disk_use(Some(true))
+            .build();
+
+        let chunks = self.0.collection(CHUNKS);
+        let mut uploads = chunks.aggregate(pipeline, Some(options)).await?;
+
+        let mut removed = 0;
+        while let Some(upload) = uploads.next().await {
+            let upload = upload?.get_object_id("_id")?.clone();
+            chunks
+                .delete_many(doc! { "files_id": upload }, None)
+                .await?;
+            removed += 1;
+        }
+
+        Ok(removed)
     }
 }
 
@@ -72,6 +524,12 @@ impl From<MongoError> for DataStoreError {
     }
 }
 
+impl From<oid::Error> for DataStoreError {
+    fn from(error: oid::Error) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
 impl From<ValueAccessError> for DataStoreError {
     fn from(error: ValueAccessError) -> Self {
         Self::Database(error.to_string())
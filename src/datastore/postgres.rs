@@ -14,16 +14,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::datastore::{entry_exists, DataStore, DataStoreError, Result};
-use crate::PgPool;
+use crate::datastore::{
+    check_duplicate, ByteStream, DataStore, DataStoreError, Result, Sample, BUFFERED_LIMIT,
+};
+use crate::{blocking, PgPool};
 
+use async_trait::async_trait;
 use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::{Binary, Integer};
+use futures::{future, stream};
 use r2d2::Error as R2d2Error;
-use reactrix::{schema, Data};
 use std::sync::Arc;
 
+sql_function!(fn substring(data: Binary, start: Integer, length: Integer) -> Binary);
+sql_function!(fn octet_length(data: Binary) -> Integer);
+
+/// Keeps blobs in a `bytea` column, so they don't stream: each one is held
+/// in memory as a whole on its way in and out, and is capped accordingly
 pub struct PostgresDataStore(Arc<PgPool>);
 
 impl PostgresDataStore {
@@ -32,38 +43,134 @@ impl PostgresDataStore {
     }
 }
 
+#[async_trait]
 impl DataStore for PostgresDataStore {
-    fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
         let hash = Blake2s::digest(data);
 
-        if entry_exists(self, &hash, data)? {
-            return Ok(hash.to_vec());
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
         }
 
-        match diesel::insert_into(schema::datastore::table)
-            .values(Data {
-                hash: hash.to_vec(),
-                data: data.to_vec(),
-            })
-            .get_result::<Data>(&self.0.get()?)
-        {
-            Ok(data) => Ok(data.hash),
-            Err(e) => Err(e.into()),
-        }
+        Ok(hash.to_vec())
     }
 
-    fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
-        use schema::datastore::dsl;
-
-        match dsl::datastore
-            .select(dsl::data)
-            .filter(dsl::hash.eq(id))
-            .first::<Vec<u8>>(&self.0.get()?)
-        {
-            Ok(data) => Ok(data),
-            Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
-            Err(e) => Err(e.into()),
-        }
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        use crate::schema::datastore::dsl;
+
+        let id = id.to_vec();
+        let data = data.to_vec();
+
+        blocking(&self.0, move |conn| loop {
+            let inserted = diesel::insert_into(dsl::datastore)
+                .values((dsl::hash.eq(&id), dsl::data.eq(&data)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            if inserted > 0 {
+                return Ok(true);
+            }
+
+            // Removed in between, so insert again
+            let touched = diesel::update(dsl::datastore.filter(dsl::hash.eq(&id)))
+                .set(dsl::created.eq(diesel::dsl::now))
+                .execute(conn)?;
+            if touched > 0 {
+                return Ok(false);
+            }
+        })
+        .await
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        use crate::schema::datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(dsl::data)
+                .filter(dsl::hash.eq(id))
+                .first::<Vec<u8>>(conn)
+            {
+                Ok(data) => Ok(data),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        use crate::schema::datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(octet_length(dsl::data))
+                .filter(dsl::hash.eq(id))
+                .first::<i32>(conn)
+            {
+                Ok(size) => Ok(size as u64),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        use crate::schema::datastore::dsl;
+
+        let id = id.to_vec();
+        // Blobs in the database stay well below 2 GB
+        let start = (offset + 1).min(i32::MAX as u64) as i32;
+        let length = length.min(i32::MAX as u64) as i32;
+
+        let data = blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(substring(dsl::data, start, length))
+                .filter(dsl::hash.eq(id))
+                .first::<Vec<u8>>(conn)
+            {
+                Ok(data) => Ok(data),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?;
+
+        Ok(Box::pin(stream::once(future::ready(Ok(Bytes::from(data))))))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        use crate::schema::datastore::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::datastore
+                .select(dsl::hash)
+                .filter(dsl::created.lt(before))
+                .load::<Vec<u8>>(conn)?)
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        use crate::schema::datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            let old = dsl::datastore
+                .filter(dsl::hash.eq(id))
+                .filter(dsl::created.lt(before));
+            Ok(diesel::delete(old).execute(conn)? > 0)
+        })
+        .await
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        Some(BUFFERED_LIMIT)
     }
 }
 
@@ -0,0 +1,583 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{
+    check_duplicate, upload_name, ByteStream, DataStore, DataStoreError, PendingId, Result, Sample,
+};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use chrono::{DateTime, Utc};
+use futures::{stream, TryStreamExt};
+use log::error;
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest,
+    ListMultipartUploadsRequest, ListObjectsV2Request, PutObjectRequest, S3Client,
+    UploadPartCopyRequest, UploadPartRequest, S3,
+};
+use std::error::Error;
+use url::Url;
+
+const DEFAULT_REGION: &str = "us-east-1";
+/// Comfortably above the minimum of 5 MiB for all but the last part
+const PART_SIZE: usize = 8 * 1024 * 1024;
+/// The most that a single copy takes
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// Copied at once beyond that, well within the limit of 10000 parts
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+const UPLOADS: &str = "uploads";
+
+/// Keeps blobs as objects named after their hex hash in an S3 bucket or
+/// anything speaking the same protocol, e.g. MinIO.
+/// Credentials come from the usual `AWS_*` environment variables.
+pub struct S3DataStore {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3DataStore {
+    /// Open the bucket of an `s3://bucket/prefix` URL, taking the options
+    /// `region` and `endpoint` (for anything other than AWS) from its query
+    pub fn open(url: &Url) -> Result<Self> {
+        let bucket = match url.host_str() {
+            Some(bucket) => bucket.to_string(),
+            None => return Err(DataStoreError::Database(format!("No bucket in {}", url))),
+        };
+
+        let mut prefix = url.path().trim_start_matches('/').to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        let mut name = DEFAULT_REGION.to_string();
+        let mut endpoint = None;
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "region" => name = value.into_owned(),
+                "endpoint" => endpoint = Some(value.into_owned()),
+                _ => {
+                    return Err(DataStoreError::Database(format!(
+                        "Unknown option {}",
+                        key
+                    )))
+                }
+            }
+        }
+
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom { name, endpoint },
+            None => name
+                .parse()
+                .map_err(|e| DataStoreError::Database(format!("{}", e)))?,
+        };
+
+        Ok(Self {
+            client: S3Client::new(region),
+            bucket,
+            prefix,
+        })
+    }
+
+    fn key(&self, hash: &[u8]) -> String {
+        format!("{}{}", self.prefix, hex::encode(hash))
+    }
+
+    async fn delete(&self, key: String) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key,
+            ..Default::default()
+        };
+
+        self.client.delete_object(request).await?;
+        Ok(())
+    }
+
+    /// Copy an object onto itself to make it young again for the collector,
+    /// telling whether it exists at all. There are no conditional writes in
+    /// S3, so this is as good as it gets. Racing writers of the same hash put
+    /// the same bytes, which is harmless.
+    async fn touch(&self, hash: &[u8]) -> Result<bool> {
+        let size = match self.size(hash).await {
+            Ok(size) => size,
+            Err(DataStoreError::NoRecord) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let key = self.key(hash);
+        self.copy(&key, &key, size).await?;
+        Ok(true)
+    }
+
+    /// Copy an object within the bucket, in parts if it's too big for one go
+    async fn copy(&self, source: &str, key: &str, size: u64) -> Result<()> {
+        let copy_source = format!("{}/{}", self.bucket, source);
+
+        if size <= MAX_COPY_SIZE {
+            let request = CopyObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                copy_source,
+                // Required to copy an object onto itself
+                metadata_directive: Some("REPLACE".to_string()),
+                ..Default::default()
+            };
+
+            self.client.copy_object(request).await?;
+            return Ok(());
+        }
+
+        let upload_id = self.create_upload(key).await?;
+        let parts = self.copy_parts(&copy_source, key, &upload_id, size).await;
+        self.finish_upload(key, upload_id, parts).await
+    }
+
+    async fn copy_parts(
+        &self,
+        copy_source: &str,
+        key: &str,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut parts = Vec::new();
+
+        for (start, part_number) in (0..size).step_by(COPY_PART_SIZE as usize).zip(1..) {
+            let end = size.min(start + COPY_PART_SIZE);
+            let request = UploadPartCopyRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                part_number,
+                copy_source: copy_source.to_string(),
+                copy_source_range: Some(format!("bytes={}-{}", start, end - 1)),
+                ..Default::default()
+            };
+
+            let output = self.client.upload_part_copy(request).await?;
+            parts.push(CompletedPart {
+                e_tag: output.copy_part_result.and_then(|result| result.e_tag),
+                part_number: Some(part_number),
+            });
+        }
+
+        Ok(parts)
+    }
+
+    async fn create_upload(&self, key: &str) -> Result<String> {
+        let request = CreateMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        match self.client.create_multipart_upload(request).await?.upload_id {
+            Some(upload_id) => Ok(upload_id),
+            None => Err(DataStoreError::Database("No upload ID".to_string())),
+        }
+    }
+
+    /// Complete a multipart upload with its parts, or abort it if they failed
+    async fn finish_upload(
+        &self,
+        key: &str,
+        upload_id: String,
+        parts: Result<Vec<CompletedPart>>,
+    ) -> Result<()> {
+        let parts = match parts {
+            Ok(parts) => parts,
+            Err(e) => {
+                let request = AbortMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.to_string(),
+                    upload_id,
+                    ..Default::default()
+                };
+                if let Err(e) = self.client.abort_multipart_upload(request).await {
+                    error!("Couldn't abort upload {}: {}", key, e);
+                }
+                return Err(e);
+            }
+        };
+
+        let request = CompleteMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id,
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+
+        self.client.complete_multipart_upload(request).await?;
+        Ok(())
+    }
+
+    async fn object_stream(&self, key: String, range: Option<String>) -> Result<ByteStream> {
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key,
+            range,
+            ..Default::default()
+        };
+
+        match self.client.get_object(request).await {
+            Ok(output) => match output.body {
+                Some(body) => Ok(Box::pin(body.map_err(DataStoreError::from))),
+                None => Err(DataStoreError::NoRecord),
+            },
+            Err(ref e) if is_missing(e) => Err(DataStoreError::NoRecord),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        data: Vec<u8>,
+    ) -> Result<CompletedPart> {
+        let request = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            part_number,
+            content_length: Some(data.len() as i64),
+            body: Some(data.into()),
+            ..Default::default()
+        };
+
+        let output = self.client.upload_part(request).await?;
+        Ok(CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    /// Upload the rest of a stream in parts and return them along with the
+    /// size of all of it
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut buffer: Vec<u8>,
+        mut data: ByteStream,
+    ) -> Result<(Vec<CompletedPart>, u64)> {
+        let mut parts = Vec::new();
+        let mut size = buffer.len() as u64;
+
+        loop {
+            while buffer.len() >= PART_SIZE {
+                let rest = buffer.split_off(PART_SIZE);
+                let number = parts.len() as i64 + 1;
+                parts.push(self.upload_part(key, upload_id, number, buffer).await?);
+                buffer = rest;
+            }
+
+            match data.try_next().await? {
+                Some(chunk) => {
+                    size += chunk.len() as u64;
+                    buffer.extend_from_slice(&chunk);
+                }
+                None => break,
+            }
+        }
+
+        if !buffer.is_empty() {
+            let number = parts.len() as i64 + 1;
+            parts.push(self.upload_part(key, upload_id, number, buffer).await?);
+        }
+
+        Ok((parts, size))
+    }
+
+    /// Upload a stream under a temporary key and copy it to its ID once
+    /// that's known
+    async fn store_upload(
+        &self,
+        key: &str,
+        buffer: Vec<u8>,
+        data: ByteStream,
+        id: PendingId,
+    ) -> Result<bool> {
+        let upload_id = self.create_upload(key).await?;
+        let uploaded = self.upload_parts(key, &upload_id, buffer, data).await;
+        let size = uploaded.as_ref().map_or(0, |(_, size)| *size);
+        self.finish_upload(key, upload_id, uploaded.map(|(parts, _)| parts))
+            .await?;
+
+        let id = id.await?;
+        if self.touch(&id).await? {
+            return Ok(false);
+        }
+
+        self.copy(key, &self.key(&id), size).await?;
+        Ok(true)
+    }
+}
+
+/// Whether a timestamp as listed is before `before`, which those that can't
+/// be told aren't
+fn is_before(timestamp: Option<String>, before: DateTime<Utc>) -> bool {
+    timestamp
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(&timestamp).ok())
+        .is_some_and(|timestamp| timestamp.with_timezone(&Utc) < before)
+}
+
+fn is_missing(error: &RusotoError<GetObjectError>) -> bool {
+    match error {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => true,
+        // Some implementations answer without an error code in the body
+        RusotoError::Unknown(response) => response.status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+fn is_missing_head(error: &RusotoError<HeadObjectError>) -> bool {
+    match error {
+        RusotoError::Service(HeadObjectError::NoSuchKey(_)) => true,
+        // Responses to HEAD requests never have a body to tell
+        RusotoError::Unknown(response) => response.status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl DataStore for S3DataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = Blake2s::digest(data);
+
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        if self.touch(id).await? {
+            return Ok(false);
+        }
+
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(id),
+            content_length: Some(data.len() as i64),
+            body: Some(data.to_vec().into()),
+            ..Default::default()
+        };
+
+        self.client.put_object(request).await?;
+        Ok(true)
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        let mut body = self.object_stream(self.key(id), None).await?;
+
+        let mut data = Vec::new();
+        while let Some(chunk) = body.try_next().await? {
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(data)
+    }
+
+    async fn store_stream_as(&self, mut data: ByteStream, id: PendingId) -> Result<bool> {
+        let mut buffer = Vec::new();
+
+        // Whatever fits in a single part goes up in one request, as with `store`
+        while buffer.len() <= PART_SIZE {
+            match data.try_next().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => return self.store_as(&id.await?, &buffer).await,
+            }
+        }
+
+        let key = format!("{}{}/{}", self.prefix, UPLOADS, upload_name());
+        let result = self.store_upload(&key, buffer, data, id).await;
+
+        if let Err(e) = self.delete(key.clone()).await {
+            error!("Couldn't remove upload {}: {}", key, e);
+        }
+
+        result
+    }
+
+    async fn retrieve_stream(&self, id: &[u8]) -> Result<ByteStream> {
+        self.object_stream(self.key(id), None).await
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(id),
+            ..Default::default()
+        };
+
+        match self.client.head_object(request).await {
+            Ok(output) => Ok(output.content_length.unwrap_or_default() as u64),
+            Err(ref e) if is_missing_head(e) => Err(DataStoreError::NoRecord),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        if length == 0 {
+            // Not expressible as a byte range
+            self.size(id).await?;
+            return Ok(Box::pin(stream::empty()));
+        }
+
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        self.object_stream(self.key(id), Some(range)).await
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(self.prefix.clone()),
+                continuation_token,
+                ..Default::default()
+            };
+
+            let output = self.client.list_objects_v2(request).await?;
+            for object in output.contents.unwrap_or_default() {
+                if !is_before(object.last_modified, before) {
+                    continue;
+                }
+
+                // Skips uploads in progress and anything else that isn't a hash
+                let key = object.key.unwrap_or_default();
+                if let Some(Ok(hash)) = key.get(self.prefix.len()..).map(hex::decode) {
+                    hashes.push(hash);
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated.unwrap_or_default() => {
+                    continuation_token = Some(token)
+                }
+                _ => return Ok(hashes),
+            }
+        }
+    }
+
+    /// There are no conditional deletes in S3, so a blob stored again between
+    /// the check of its age and the delete is still lost
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(id),
+            ..Default::default()
+        };
+
+        let modified = match self.client.head_object(request).await {
+            // A header, unlike the timestamps of listings
+            Ok(output) => output
+                .last_modified
+                .and_then(|modified| DateTime::parse_from_rfc2822(&modified).ok()),
+            Err(ref e) if is_missing_head(e) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if modified.is_none_or(|modified| modified.with_timezone(&Utc) >= before) {
+            return Ok(false);
+        }
+
+        self.delete(self.key(id)).await?;
+        Ok(true)
+    }
+
+    /// Objects of uploads that weren't copied to their ID and removed, and
+    /// multipart uploads that were neither completed nor aborted
+    async fn remove_uploads(&self, before: DateTime<Utc>) -> Result<usize> {
+        let prefix = format!("{}{}/", self.prefix, UPLOADS);
+        let mut removed = 0;
+        let mut continuation_token = None;
+
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.clone()),
+                continuation_token,
+                ..Default::default()
+            };
+
+            let output = self.client.list_objects_v2(request).await?;
+            for object in output.contents.unwrap_or_default() {
+                if let (Some(key), true) = (object.key, is_before(object.last_modified, before)) {
+                    self.delete(key).await?;
+                    removed += 1;
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated.unwrap_or_default() => {
+                    continuation_token = Some(token)
+                }
+                _ => break,
+            }
+        }
+
+        let (mut key_marker, mut upload_id_marker) = (None, None);
+        loop {
+            let request = ListMultipartUploadsRequest {
+                bucket: self.bucket.clone(),
+                prefix: Some(self.prefix.clone()),
+                key_marker,
+                upload_id_marker,
+                ..Default::default()
+            };
+
+            let output = self.client.list_multipart_uploads(request).await?;
+            for upload in output.uploads.unwrap_or_default() {
+                if !is_before(upload.initiated, before) {
+                    continue;
+                }
+
+                if let (Some(key), Some(upload_id)) = (upload.key, upload.upload_id) {
+                    let request = AbortMultipartUploadRequest {
+                        bucket: self.bucket.clone(),
+                        key,
+                        upload_id,
+                        ..Default::default()
+                    };
+                    self.client.abort_multipart_upload(request).await?;
+                    removed += 1;
+                }
+            }
+
+            if !output.is_truncated.unwrap_or_default() {
+                return Ok(removed);
+            }
+            key_marker = output.next_key_marker;
+            upload_id_marker = output.next_upload_id_marker;
+        }
+    }
+}
+
+impl<E: Error + 'static> From<RusotoError<E>> for DataStoreError {
+    fn from(error: RusotoError<E>) -> Self {
+        Self::Database(error.to_string())
+    }
+}
@@ -0,0 +1,178 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{
+    check_duplicate, ByteStream, DataStore, DataStoreError, Result, Sample, BUFFERED_LIMIT,
+};
+use crate::schema::sqlite::datastore;
+use crate::{blocking, SqlitePool};
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::{Binary, Integer};
+use futures::{future, stream};
+use std::sync::Arc;
+
+sql_function!(fn substr(data: Binary, start: Integer, length: Integer) -> Binary);
+sql_function!(fn length(data: Binary) -> Integer);
+
+/// Keeps blobs in a `BLOB` column, so they don't stream: each one is held
+/// in memory as a whole on its way in and out, and is capped accordingly
+pub struct SqliteDataStore(Arc<SqlitePool>);
+
+impl SqliteDataStore {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self(pool)
+    }
+}
+
+#[async_trait]
+impl DataStore for SqliteDataStore {
+    async fn store(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = Blake2s::digest(data);
+
+        if !self.store_as(&hash, data).await? {
+            check_duplicate(self, &hash, &Sample::of(data)).await?;
+        }
+
+        Ok(hash.to_vec())
+    }
+
+    async fn store_as(&self, id: &[u8], data: &[u8]) -> Result<bool> {
+        use datastore::dsl;
+
+        let id = id.to_vec();
+        let data = data.to_vec();
+
+        blocking(&self.0, move |conn| loop {
+            let inserted = diesel::insert_or_ignore_into(dsl::datastore)
+                .values((
+                    dsl::hash.eq(&id),
+                    dsl::data.eq(&data),
+                    dsl::created.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+            if inserted > 0 {
+                return Ok(true);
+            }
+
+            // Removed in between, so insert again
+            let touched = diesel::update(dsl::datastore.filter(dsl::hash.eq(&id)))
+                .set(dsl::created.eq(Utc::now().naive_utc()))
+                .execute(conn)?;
+            if touched > 0 {
+                return Ok(false);
+            }
+        })
+        .await
+    }
+
+    async fn retrieve(&self, id: &[u8]) -> Result<Vec<u8>> {
+        use datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(dsl::data)
+                .filter(dsl::hash.eq(id))
+                .first::<Vec<u8>>(conn)
+            {
+                Ok(data) => Ok(data),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn size(&self, id: &[u8]) -> Result<u64> {
+        use datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(length(dsl::data))
+                .filter(dsl::hash.eq(id))
+                .first::<i32>(conn)
+            {
+                Ok(size) => Ok(size as u64),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn retrieve_range(&self, id: &[u8], offset: u64, length: u64) -> Result<ByteStream> {
+        use datastore::dsl;
+
+        let id = id.to_vec();
+        // Blobs in the database stay well below 2 GB
+        let start = (offset + 1).min(i32::MAX as u64) as i32;
+        let length = length.min(i32::MAX as u64) as i32;
+
+        let data = blocking(&self.0, move |conn| {
+            match dsl::datastore
+                .select(substr(dsl::data, start, length))
+                .filter(dsl::hash.eq(id))
+                .first::<Vec<u8>>(conn)
+            {
+                Ok(data) => Ok(data),
+                Err(DieselError::NotFound) => Err(DataStoreError::NoRecord),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?;
+
+        Ok(Box::pin(stream::once(future::ready(Ok(Bytes::from(data))))))
+    }
+
+    async fn list(&self, before: DateTime<Utc>) -> Result<Vec<Vec<u8>>> {
+        use datastore::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::datastore
+                .select(dsl::hash)
+                .filter(dsl::created.lt(before.naive_utc()))
+                .load::<Vec<u8>>(conn)?)
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &[u8], before: DateTime<Utc>) -> Result<bool> {
+        use datastore::dsl;
+
+        let id = id.to_vec();
+
+        blocking(&self.0, move |conn| {
+            let old = dsl::datastore
+                .filter(dsl::hash.eq(id))
+                .filter(dsl::created.lt(before.naive_utc()));
+            Ok(diesel::delete(old).execute(conn)? > 0)
+        })
+        .await
+    }
+
+    fn max_blob_size(&self) -> Option<u64> {
+        Some(BUFFERED_LIMIT)
+    }
+}
@@ -14,13 +14,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod file;
+mod memory;
 mod mongo;
 mod postgres;
+mod shredding;
+mod sqlite;
 
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
 use failure::Fail;
+pub use file::*;
+pub use memory::*;
 pub use mongo::*;
 pub use postgres::*;
+pub use shredding::*;
+pub use sqlite::*;
 use reactrix::{Event, NewEvent};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::task::JoinError;
 
 #[derive(Debug, Fail)]
 pub enum EventStoreError {
@@ -28,12 +41,317 @@ pub enum EventStoreError {
     Database(String),
     #[fail(display = "Record not found")]
     NoRecord,
+    #[fail(display = "Expected state doesn't hold anymore")]
+    Conflict,
+    #[fail(display = "Events are bigger than {} bytes", 0)]
+    TooBig(u64),
+    #[fail(display = "Invalid event: {}", 0)]
+    Invalid(String),
 }
 
 pub type Result<T> = std::result::Result<T, EventStoreError>;
 
+#[derive(Serialize)]
+pub struct StreamPosition {
+    pub sequence: i64,
+    pub revision: i64,
+}
+
+#[derive(Serialize)]
+pub struct StreamEvent {
+    pub stream: String,
+    pub revision: i64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// An event's place in the hash chain
+pub struct Link {
+    pub sequence: i64,
+    /// Missing for events stored before there was a chain
+    pub hash: Option<Vec<u8>>,
+}
+
+/// JSON with the keys of objects sorted, as backends don't agree on an order
+fn canonical(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                canonical(item, out);
+            }
+            out.push(b']');
+        }
+        Value::Object(fields) => {
+            let mut fields = fields.iter().collect::<Vec<_>>();
+            fields.sort_by_key(|(a, _)| *a);
+
+            out.push(b'{');
+            for (i, (key, value)) in fields.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(Value::String(key.clone()).to_string().as_bytes());
+                out.push(b':');
+                canonical(value, out);
+            }
+            out.push(b'}');
+        }
+        _ => out.extend_from_slice(value.to_string().as_bytes()),
+    }
+}
+
+/// Unambiguous encoding of everything about an event, for hashing
+pub fn event_bytes(event: &Event) -> Vec<u8> {
+    let mut data = Vec::new();
+    canonical(&event.data, &mut data);
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&event.sequence.to_le_bytes());
+    bytes.extend_from_slice(&event.version.to_le_bytes());
+    bytes.extend_from_slice(&(event.type_.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(event.type_.as_bytes());
+    bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&data);
+    bytes.extend_from_slice(&event.timestamp.timestamp_nanos().to_le_bytes());
+    bytes
+}
+
+/// Hash of an event chained to the hash of the one before it
+pub fn chain_hash(previous: Option<&[u8]>, event: &Event) -> Vec<u8> {
+    let mut hasher = Blake2s::new();
+    hasher.input(previous.unwrap_or_default());
+    hasher.input(event_bytes(event));
+    hasher.result().to_vec()
+}
+
+impl From<JoinError> for EventStoreError {
+    fn from(error: JoinError) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+#[async_trait]
 pub trait EventStore: Send + Sync {
-    fn store(&self, data: NewEvent) -> Result<i64>;
-    fn retrieve(&self, id: i64) -> Result<Event>;
-    fn sequence(&self) -> Result<i64>;
+    /// Append an event, optionally only if `expected` is still the latest
+    /// sequence (`-1` for an empty store)
+    async fn store(&self, data: NewEvent, expected: Option<i64>) -> Result<i64>;
+    /// Append all events or none of them, with contiguous sequences. As a
+    /// batch is written in one go, backends limit its size and refuse bigger
+    /// ones with `TooBig`: MongoDB to less than its 16 MB per document, as
+    /// all of it passes through the counter, the file log to its frame size.
+    async fn store_batch(&self, data: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>>;
+    /// Append an event to a stream, optionally only if `expected` is still
+    /// the stream's latest revision (`-1` for a new stream)
+    async fn append(
+        &self,
+        stream: &str,
+        data: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition>;
+    async fn retrieve(&self, id: i64) -> Result<Event>;
+    /// Events become visible in order of sequence: once an event can be read,
+    /// all events before it can be too, and a gap below it is never filled.
+    /// Readers that follow the log rely on this.
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>>;
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>>;
+    async fn sequence(&self) -> Result<i64>;
+    /// Sequences that were stored but not yet announced, oldest first
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>>;
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()>;
+    /// The hash chain from `from` on, in order of sequence
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use chrono::{TimeZone, Utc};
+    use futures::future;
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn event(data: Value) -> Event {
+        Event {
+            sequence: 7,
+            version: 1,
+            type_: "test".to_string(),
+            data,
+            timestamp: Utc.timestamp(1_594_000_000, 0),
+        }
+    }
+
+    fn canonical_string(value: &Value) -> String {
+        let mut out = Vec::new();
+        canonical(value, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn keys_are_sorted_at_every_depth() {
+        let value = json!({ "b": [1, { "d": "x", "c": null }], "a": "\"q\"" });
+        let expected = r#"{"a":"\"q\"","b":[1,{"c":null,"d":"x"}]}"#;
+        assert_eq!(canonical_string(&value), expected);
+    }
+
+    #[test]
+    fn order_of_keys_doesnt_matter() {
+        let a = serde_json::from_str(r#"{"x": 1, "y": {"p": true, "q": [2.5]}}"#).unwrap();
+        let b = serde_json::from_str(r#"{"y": {"q": [2.5], "p": true}, "x": 1}"#).unwrap();
+        assert_eq!(chain_hash(None, &event(a)), chain_hash(None, &event(b)));
+    }
+
+    #[test]
+    fn hashes_cover_every_field() {
+        let original = event(json!({ "x": 1 }));
+        let hash = chain_hash(Some(b"previous"), &original);
+
+        let mut changed = Vec::new();
+        for i in 0..5 {
+            let mut event = event(json!({ "x": 1 }));
+            match i {
+                0 => event.sequence += 1,
+                1 => event.version += 1,
+                2 => event.type_.push('s'),
+                3 => event.data = json!({ "x": 2 }),
+                _ => event.timestamp = event.timestamp + chrono::Duration::nanoseconds(1),
+            }
+            changed.push(chain_hash(Some(b"previous"), &event));
+        }
+        changed.push(chain_hash(Some(b"other"), &original));
+        changed.push(chain_hash(None, &original));
+
+        assert!(changed.iter().all(|other| *other != hash));
+    }
+
+    fn new_event(i: i64) -> NewEvent {
+        NewEvent {
+            version: 1,
+            type_: "test".to_string(),
+            data: json!({ "i": i }),
+        }
+    }
+
+    fn is_conflict<T>(result: Result<T>) -> bool {
+        matches!(result, Err(EventStoreError::Conflict))
+    }
+
+    /// A path of its own for each test, with nothing there yet
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("reactrix-events-{}-{}", process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn open_sqlite(path: &Path) -> SqliteEventStore {
+        let pool = sqlite::connect(path.to_str().unwrap()).unwrap();
+        SqliteEventStore::new(Arc::new(pool))
+    }
+
+    fn open_file(path: &Path) -> FileEventStore {
+        FileEventStore::open(&Url::from_directory_path(path).unwrap()).unwrap()
+    }
+
+    async fn stale_expectations_conflict(store: &dyn EventStore) {
+        let first = store.store(new_event(0), Some(-1)).await.unwrap();
+        assert!(is_conflict(store.store(new_event(1), Some(-1)).await));
+        let second = store.store(new_event(1), Some(first)).await.unwrap();
+        assert_eq!(second, first + 1);
+
+        let position = store.append("s", new_event(2), Some(-1)).await.unwrap();
+        assert_eq!((position.sequence, position.revision), (second + 1, 0));
+        assert!(is_conflict(store.append("s", new_event(3), Some(-1)).await));
+        let position = store.append("s", new_event(3), Some(0)).await.unwrap();
+        assert_eq!((position.sequence, position.revision), (second + 2, 1));
+        let position = store.append("s", new_event(4), None).await.unwrap();
+        assert_eq!(position.revision, 2);
+
+        assert!(is_conflict(store.store(new_event(5), Some(second)).await));
+        assert_eq!(store.sequence().await.unwrap(), position.sequence);
+    }
+
+    async fn batches_are_contiguous_and_whole(store: &dyn EventStore) {
+        let batch = || (0..5).map(new_event).collect::<Vec<_>>();
+        let (a, b) = future::join(
+            store.store_batch(batch(), None),
+            store.store_batch(batch(), None),
+        )
+        .await;
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        for sequences in [&a, &b] {
+            assert_eq!(sequences.len(), 5);
+            assert!(sequences.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        }
+        assert!(a[4] < b[0] || b[4] < a[0]);
+
+        // One stale expectation and none of them go in
+        let (stale, latest) = (a[4].min(b[4]), a[4].max(b[4]));
+        assert!(is_conflict(store.store_batch(batch(), Some(stale)).await));
+        assert_eq!(store.sequence().await.unwrap(), latest);
+        assert_eq!(store.retrieve_range(0, 100).await.unwrap().len(), 10);
+        assert!(store.store_batch(batch(), Some(latest)).await.is_ok());
+    }
+
+    /// Returns the one left unacknowledged
+    async fn stored_events_are_pending(store: &dyn EventStore) -> i64 {
+        let first = store.store(new_event(0), None).await.unwrap();
+        let batch = vec![new_event(1), new_event(2)];
+        let sequences = store.store_batch(batch, None).await.unwrap();
+        let pending = vec![first, sequences[0], sequences[1]];
+        assert_eq!(store.pending(10).await.unwrap(), pending);
+
+        store.acknowledge(&[first, sequences[1]]).await.unwrap();
+        assert_eq!(store.pending(10).await.unwrap(), vec![sequences[0]]);
+        sequences[0]
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_the_contract() {
+        stale_expectations_conflict(&MemoryEventStore::new()).await;
+        batches_are_contiguous_and_whole(&MemoryEventStore::new()).await;
+        stored_events_are_pending(&MemoryEventStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_keeps_the_contract() {
+        let dir = scratch("sqlite");
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = ["stale.db", "batch.db", "outbox.db"].map(|name| dir.join(name));
+        stale_expectations_conflict(&open_sqlite(&paths[0])).await;
+        batches_are_contiguous_and_whole(&open_sqlite(&paths[1])).await;
+        let pending = stored_events_are_pending(&open_sqlite(&paths[2])).await;
+
+        // Pending events outlive a restart
+        let after = open_sqlite(&paths[2]).pending(10).await.unwrap();
+        assert_eq!(after, vec![pending]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_keeps_the_contract() {
+        let paths = [scratch("stale"), scratch("batch"), scratch("outbox")];
+        stale_expectations_conflict(&open_file(&paths[0])).await;
+        batches_are_contiguous_and_whole(&open_file(&paths[1])).await;
+        let pending = stored_events_are_pending(&open_file(&paths[2])).await;
+
+        // Only the oldest pending one is recorded, so what follows comes again
+        let after = open_file(&paths[2]).pending(10).await.unwrap();
+        assert_eq!(after.first(), Some(&pending));
+        for path in &paths {
+            std::fs::remove_dir_all(path).unwrap();
+        }
+    }
 }
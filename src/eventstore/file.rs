@@ -0,0 +1,1096 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{
+    chain_hash, EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition,
+};
+
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use fs2::FileExt;
+use log::{info, warn};
+use reactrix::{Event, NewEvent};
+use rmp_serde as rmp;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::task;
+use url::Url;
+
+const HEADER_SIZE: usize = 8;
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+const INDEX_INTERVAL: i64 = 64;
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const PUBLISHED: &str = "published";
+const LOCK: &str = "lock";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fsync {
+    /// After every append
+    Always,
+    /// After every n appends
+    Every(u32),
+    /// Whenever the OS sees fit
+    Never,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    sequence: i64,
+    version: i32,
+    type_: String,
+    data: Value,
+    /// Nanoseconds since the epoch
+    timestamp: i64,
+    stream: Option<String>,
+    revision: Option<i64>,
+    /// Missing from logs written before the hash chain
+    #[serde(default)]
+    hash: Option<Vec<u8>>,
+}
+
+impl Entry {
+    fn new(event: NewEvent, sequence: i64, stream: Option<(String, i64)>) -> Self {
+        let (stream, revision) = match stream {
+            Some((stream, revision)) => (Some(stream), Some(revision)),
+            None => (None, None),
+        };
+
+        Self {
+            sequence,
+            version: event.version,
+            type_: event.type_,
+            data: event.data,
+            timestamp: Utc::now().timestamp_nanos(),
+            stream,
+            revision,
+            hash: None,
+        }
+    }
+
+    fn to_event(&self) -> Event {
+        Event {
+            sequence: self.sequence,
+            version: self.version,
+            type_: self.type_.clone(),
+            data: self.data.clone(),
+            timestamp: Utc.timestamp_nanos(self.timestamp),
+        }
+    }
+
+    fn into_event(self) -> Event {
+        Event {
+            sequence: self.sequence,
+            version: self.version,
+            type_: self.type_,
+            data: self.data,
+            timestamp: Utc.timestamp_nanos(self.timestamp),
+        }
+    }
+}
+
+/// Where a frame starts
+#[derive(Clone, Copy)]
+struct Position {
+    segment: i64,
+    offset: u64,
+}
+
+struct State {
+    /// The active segment, the only one ever written to, opened for reading
+    /// only when the log is
+    file: File,
+    segment: i64,
+    size: u64,
+    next: i64,
+    unsynced: u32,
+    segments: BTreeSet<i64>,
+    /// Frames by their first sequence, at least every INDEX_INTERVAL
+    /// sequences and for the start of every segment
+    sparse: BTreeMap<i64, Position>,
+    last_indexed: i64,
+    /// Sequences and frames of each stream, indexed by revision
+    streams: HashMap<String, Vec<(i64, Position)>>,
+    pending: BTreeSet<i64>,
+    published: i64,
+    /// Hash of the last event, the next one chains to it
+    last_hash: Option<Vec<u8>>,
+}
+
+struct Log {
+    dir: PathBuf,
+    fsync: Fsync,
+    segment_size: u64,
+    /// Held for as long as the log is open, so that only one process writes
+    /// to it and recovers it; none when it is opened read-only
+    lock: Option<File>,
+    state: Mutex<State>,
+}
+
+/// Event log in append-only segment files named after their first sequence.
+/// Every append is one frame of a length, a CRC32 of the payload and the
+/// msgpack-encoded events, so a batch is all-or-nothing even across crashes.
+pub struct FileEventStore(Arc<Log>);
+
+fn segment_path(dir: &Path, first: i64) -> PathBuf {
+    dir.join(format!("{:020}.log", first))
+}
+
+fn corrupt(path: &Path) -> EventStoreError {
+    EventStoreError::Database(format!("Corrupt segment {}", path.display()))
+}
+
+/// Read the next frame, or nothing at the end of the valid part of a segment
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(u64, Vec<Entry>)>> {
+    let mut header = [0; HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut length = [0; 4];
+    let mut checksum = [0; 4];
+    length.copy_from_slice(&header[..4]);
+    checksum.copy_from_slice(&header[4..]);
+    let length = u32::from_le_bytes(length) as usize;
+
+    // Frames are never empty, but a file extended by a crash may read as such
+    if length == 0 || length > MAX_FRAME_SIZE {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; length];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if crc32fast::hash(&payload) != u32::from_le_bytes(checksum) {
+        return Ok(None);
+    }
+
+    let entries = rmp::from_read_ref(&payload)
+        .map_err(|e| EventStoreError::Database(format!("Couldn't decode frame: {}", e)))?;
+
+    Ok(Some(((HEADER_SIZE + length) as u64, entries)))
+}
+
+/// Whether what follows the valid frames of a segment is a single write cut
+/// short, rather than damage to frames that came before others
+fn is_torn(rest: &[u8]) -> bool {
+    if rest.len() < HEADER_SIZE || rest.iter().all(|&byte| byte == 0) {
+        return true;
+    }
+
+    let mut length = [0; 4];
+    length.copy_from_slice(&rest[..4]);
+    HEADER_SIZE as u64 + u32::from_le_bytes(length) as u64 >= rest.len() as u64
+}
+
+/// Refuse to go on without the events from `next` on if the outbox has
+/// published them already, as their sequences would be handed out again
+fn check_published(dir: &Path, next: i64, published: i64) -> Result<()> {
+    if published >= next {
+        return Err(EventStoreError::Database(format!(
+            "Event log {} lost events up to {}, which were published already",
+            dir.display(),
+            published
+        )));
+    }
+    Ok(())
+}
+
+/// Move segments aside that follow a lost write and can't be reached anymore.
+/// Read-only, they are only left out, for the writer to move aside.
+fn discard(
+    dir: &Path,
+    discarded: &[i64],
+    segments: &mut BTreeSet<i64>,
+    read_only: bool,
+) -> Result<()> {
+    for &segment in discarded {
+        if !read_only {
+            let path = segment_path(dir, segment);
+            // Leave those of earlier crashes alone
+            let mut n = 0;
+            while path.with_extension(format!("{}.discarded", n)).exists() {
+                n += 1;
+            }
+            warn!("Discarding segment {} after a lost write", path.display());
+            fs::rename(&path, path.with_extension(format!("{}.discarded", n)))?;
+        }
+        segments.remove(&segment);
+    }
+    Ok(())
+}
+
+/// Lock a directory for writing, without waiting for anyone holding it
+fn lock(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK))?;
+
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(EventStoreError::Database(format!(
+                "Event log {} is in use by another process",
+                dir.display()
+            )))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_published(dir: &Path) -> Result<i64> {
+    match fs::read(dir.join(PUBLISHED)) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut published = [0; 8];
+            published.copy_from_slice(&bytes);
+            Ok(i64::from_le_bytes(published))
+        }
+        Ok(_) => Err(EventStoreError::Database(format!(
+            "Corrupt {}",
+            dir.join(PUBLISHED).display()
+        ))),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(-1),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_published(dir: &Path, published: i64) -> Result<()> {
+    let temporary = dir.join(format!("{}.tmp", PUBLISHED));
+    fs::write(&temporary, published.to_le_bytes())?;
+    fs::rename(temporary, dir.join(PUBLISHED))?;
+    Ok(())
+}
+
+impl Log {
+    /// Open the log for writing, recovering it from a crash if need be
+    fn open(dir: &Path, fsync: Fsync, segment_size: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Self::load(dir, fsync, segment_size, Some(lock(dir)?))
+    }
+
+    /// Open the log as far as it is valid, leaving it as it is for whoever
+    /// writes to it
+    fn open_read_only(dir: &Path, fsync: Fsync) -> Result<Self> {
+        Self::load(dir, fsync, DEFAULT_SEGMENT_SIZE, None)
+    }
+
+    fn load(dir: &Path, fsync: Fsync, segment_size: u64, lock: Option<File>) -> Result<Self> {
+        let read_only = lock.is_none();
+        let mut segments = BTreeSet::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".log") {
+                if let Ok(first) = name.trim_end_matches(".log").parse::<i64>() {
+                    segments.insert(first);
+                }
+            }
+        }
+
+        if segments.is_empty() {
+            if read_only {
+                return Err(EventStoreError::Database(format!(
+                    "No event log in {}",
+                    dir.display()
+                )));
+            }
+            segments.insert(0);
+        }
+
+        // Without fsync, a segment may lose its tail while the next one
+        // makes it to disk
+        let lossy = matches!(fsync, Fsync::Never);
+        let published = read_published(dir)?;
+        let listed = segments.iter().copied().collect::<Vec<_>>();
+        let mut next = 0;
+        let mut size = 0;
+        let mut sparse = BTreeMap::new();
+        let mut last_indexed = i64::MIN;
+        let mut streams: HashMap<String, Vec<(i64, Position)>> = HashMap::new();
+        let mut last_hash = None;
+
+        for (i, &segment) in listed.iter().enumerate() {
+            let path = segment_path(dir, segment);
+            if segment != next {
+                if !lossy || i == 0 || segment < next {
+                    return Err(corrupt(&path));
+                }
+                check_published(dir, next, published)?;
+                discard(dir, &listed[i..], &mut segments, read_only)?;
+                break;
+            }
+
+            let mut reader = BufReader::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(!read_only)
+                    .create(!read_only)
+                    .truncate(false)
+                    .open(&path)?,
+            );
+            let mut offset = 0;
+
+            while let Some((length, entries)) = read_frame(&mut reader)? {
+                let position = Position { segment, offset };
+
+                for entry in &entries {
+                    if entry.sequence != next {
+                        return Err(corrupt(&path));
+                    }
+                    if let Some(ref stream) = entry.stream {
+                        streams
+                            .entry(stream.clone())
+                            .or_default()
+                            .push((entry.sequence, position));
+                    }
+                    last_hash = entry.hash.clone();
+                    next += 1;
+                }
+
+                if let Some(first) = entries.first() {
+                    if offset == 0 || first.sequence >= last_indexed + INDEX_INTERVAL {
+                        sparse.insert(first.sequence, position);
+                        last_indexed = first.sequence;
+                    }
+                }
+
+                offset += length;
+            }
+
+            size = offset;
+            let mut file = reader.into_inner();
+            if offset == file.metadata()?.len() {
+                continue;
+            }
+
+            let mut rest = Vec::new();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_to_end(&mut rest)?;
+            let last = i + 1 == listed.len();
+            if !is_torn(&rest) || !(last || lossy) {
+                return Err(EventStoreError::Database(format!(
+                    "Corrupt frame at {} in {}",
+                    offset,
+                    path.display()
+                )));
+            }
+
+            check_published(dir, next, published)?;
+            if !read_only {
+                warn!("Discarding torn write at {} in {}", offset, path.display());
+                file.set_len(offset)?;
+                file.sync_all()?;
+            }
+
+            if !last {
+                discard(dir, &listed[i + 1..], &mut segments, read_only)?;
+                break;
+            }
+        }
+
+        let active = *segments.iter().next_back().unwrap();
+        // Also catches segments that went missing at the end
+        check_published(dir, next, published)?;
+        info!("Opened event log {} up to sequence {}", dir.display(), next - 1);
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fsync,
+            segment_size,
+            lock,
+            state: Mutex::new(State {
+                file: OpenOptions::new()
+                    .read(read_only)
+                    .write(!read_only)
+                    .open(segment_path(dir, active))?,
+                segment: active,
+                size,
+                next,
+                unsynced: 0,
+                segments,
+                sparse,
+                last_indexed,
+                streams,
+                pending: (published + 1..next).collect(),
+                published,
+                last_hash,
+            }),
+        })
+    }
+
+    fn writable(&self) -> Result<()> {
+        match self.lock {
+            Some(_) => Ok(()),
+            None => Err(EventStoreError::Database(format!(
+                "Event log {} is open read-only",
+                self.dir.display()
+            ))),
+        }
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|e| EventStoreError::Database(e.to_string()))
+    }
+
+    fn sync(&self, state: &mut State) -> io::Result<()> {
+        match self.fsync {
+            Fsync::Always => state.file.sync_data(),
+            Fsync::Every(n) => {
+                state.unsynced += 1;
+                if state.unsynced >= n {
+                    state.unsynced = 0;
+                    state.file.sync_data()
+                } else {
+                    Ok(())
+                }
+            }
+            Fsync::Never => Ok(()),
+        }
+    }
+
+    fn roll(&self, state: &mut State, first: i64) -> Result<()> {
+        let durable = !matches!(self.fsync, Fsync::Never);
+        if durable {
+            state.file.sync_data()?;
+        }
+
+        state.file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(segment_path(&self.dir, first))?;
+
+        if durable {
+            // Make the new segment's directory entry stick as well
+            File::open(&self.dir)?.sync_all()?;
+        }
+
+        state.segment = first;
+        state.size = 0;
+        state.unsynced = 0;
+        state.segments.insert(first);
+        Ok(())
+    }
+
+    fn write(&self, state: &mut State, mut entries: Vec<Entry>) -> Result<()> {
+        let mut previous = state.last_hash.clone();
+        for entry in &mut entries {
+            entry.hash = Some(chain_hash(previous.as_deref(), &entry.to_event()));
+            previous = entry.hash.clone();
+        }
+
+        let payload = rmp::to_vec(&entries)
+            .map_err(|e| EventStoreError::Database(format!("Couldn't encode frame: {}", e)))?;
+        // Anything bigger wouldn't be read back
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(EventStoreError::TooBig(MAX_FRAME_SIZE as u64));
+        }
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        if state.size > 0 && state.size + frame.len() as u64 > self.segment_size {
+            self.roll(state, entries[0].sequence)?;
+        }
+
+        let position = Position {
+            segment: state.segment,
+            offset: state.size,
+        };
+
+        // Always write at the known end so a failed write gets overwritten
+        // by the next one instead of being built upon
+        let result = state
+            .file
+            .seek(SeekFrom::Start(position.offset))
+            .and_then(|_| state.file.write_all(&frame))
+            .and_then(|_| self.sync(state));
+
+        if let Err(e) = result {
+            let _ = state.file.set_len(position.offset);
+            return Err(e.into());
+        }
+
+        state.size += frame.len() as u64;
+
+        let first = entries[0].sequence;
+        if position.offset == 0 || first >= state.last_indexed + INDEX_INTERVAL {
+            state.sparse.insert(first, position);
+            state.last_indexed = first;
+        }
+
+        for entry in &entries {
+            if let Some(ref stream) = entry.stream {
+                state
+                    .streams
+                    .entry(stream.clone())
+                    .or_default()
+                    .push((entry.sequence, position));
+            }
+            state.pending.insert(entry.sequence);
+        }
+
+        state.next = first + entries.len() as i64;
+        state.last_hash = previous;
+        Ok(())
+    }
+
+    fn store(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        self.writable()?;
+        let mut state = self.state()?;
+
+        if expected.is_some_and(|expected| expected != state.next - 1) {
+            return Err(EventStoreError::Conflict);
+        }
+
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let entries = events
+            .into_iter()
+            .zip(state.next..)
+            .map(|(event, sequence)| Entry::new(event, sequence, None))
+            .collect::<Vec<_>>();
+        let sequences = entries.iter().map(|entry| entry.sequence).collect();
+
+        self.write(&mut state, entries)?;
+        Ok(sequences)
+    }
+
+    fn append(
+        &self,
+        stream: String,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        self.writable()?;
+        let mut state = self.state()?;
+
+        let revision = state.streams.get(&stream).map_or(0, Vec::len) as i64;
+        if expected.is_some_and(|expected| expected != revision - 1) {
+            return Err(EventStoreError::Conflict);
+        }
+
+        let sequence = state.next;
+        let entry = Entry::new(event, sequence, Some((stream, revision)));
+        self.write(&mut state, vec![entry])?;
+
+        Ok(StreamPosition { sequence, revision })
+    }
+
+    fn read(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        Ok(self
+            .read_entries(from, limit)?
+            .into_iter()
+            .map(Entry::into_event)
+            .collect())
+    }
+
+    fn read_entries(&self, from: i64, limit: i64) -> Result<Vec<Entry>> {
+        let (start, segments, end) = {
+            let state = self.state()?;
+            let start = match state.sparse.range(..=from).next_back() {
+                Some((_, position)) => *position,
+                None => match state.sparse.values().next() {
+                    Some(position) => *position,
+                    None => return Ok(Vec::new()),
+                },
+            };
+
+            let segments = state
+                .segments
+                .range(start.segment..)
+                .copied()
+                .collect::<Vec<_>>();
+            (start, segments, state.next)
+        };
+
+        let mut entries = Vec::new();
+        let mut offset = start.offset;
+
+        for segment in segments {
+            let mut reader = BufReader::new(File::open(segment_path(&self.dir, segment))?);
+            reader.seek(SeekFrom::Start(offset))?;
+            offset = 0;
+
+            while let Some((_, frame)) = read_frame(&mut reader)? {
+                for entry in frame {
+                    if entry.sequence >= end || entries.len() as i64 >= limit {
+                        return Ok(entries);
+                    }
+                    if entry.sequence >= from {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_at(&self, position: Position, sequence: i64) -> Result<Event> {
+        let path = segment_path(&self.dir, position.segment);
+        let mut reader = BufReader::new(File::open(&path)?);
+        reader.seek(SeekFrom::Start(position.offset))?;
+
+        match read_frame(&mut reader)? {
+            Some((_, entries)) => entries
+                .into_iter()
+                .find(|entry| entry.sequence == sequence)
+                .map(Entry::into_event)
+                .ok_or_else(|| corrupt(&path)),
+            None => Err(corrupt(&path)),
+        }
+    }
+
+    fn read_stream(&self, stream: &str, from: i64, limit: i64) -> Result<Vec<StreamEvent>> {
+        let from = from.max(0);
+        let positions = match self.state()?.streams.get(stream) {
+            Some(positions) => positions
+                .iter()
+                .skip(from as usize)
+                .take(limit as usize)
+                .copied()
+                .collect::<Vec<_>>(),
+            None => return Ok(Vec::new()),
+        };
+
+        positions
+            .into_iter()
+            .zip(from..)
+            .map(|((sequence, position), revision)| {
+                Ok(StreamEvent {
+                    stream: stream.to_string(),
+                    revision,
+                    event: self.read_at(position, sequence)?,
+                })
+            })
+            .collect()
+    }
+
+    fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        self.writable()?;
+        let mut state = self.state()?;
+
+        for sequence in sequences {
+            state.pending.remove(sequence);
+        }
+
+        // Everything up to the oldest unacknowledged sequence is done
+        let published = match state.pending.iter().next() {
+            Some(sequence) => sequence - 1,
+            None => state.next - 1,
+        };
+
+        if published != state.published {
+            write_published(&self.dir, published)?;
+            state.published = published;
+        }
+
+        Ok(())
+    }
+}
+
+impl FileEventStore {
+    /// Open the log in the directory of a `file://` URL, taking the options
+    /// `fsync` (`always`, `never` or a number of appends) and `segment-size`
+    /// (in bytes) from its query. Fails if another process has it open.
+    pub fn open(url: &Url) -> Result<Self> {
+        let (dir, fsync, segment_size) = Self::options(url)?;
+        Ok(Self(Arc::new(Log::open(&dir, fsync, segment_size)?)))
+    }
+
+    /// Open the log without locking or recovering it, alongside a process
+    /// that writes to it. Only what has been written by then can be read.
+    pub fn open_read_only(url: &Url) -> Result<Self> {
+        let (dir, fsync, _) = Self::options(url)?;
+        Ok(Self(Arc::new(Log::open_read_only(&dir, fsync)?)))
+    }
+
+    fn options(url: &Url) -> Result<(PathBuf, Fsync, u64)> {
+        let dir = url
+            .to_file_path()
+            .map_err(|_| EventStoreError::Database(format!("Invalid path in {}", url)))?;
+
+        let mut fsync = Fsync::Always;
+        let mut segment_size = DEFAULT_SEGMENT_SIZE;
+
+        for (key, value) in url.query_pairs() {
+            let invalid = || EventStoreError::Database(format!("Invalid {} {}", key, value));
+
+            match key.as_ref() {
+                "fsync" => {
+                    fsync = match value.as_ref() {
+                        "always" => Fsync::Always,
+                        "never" => Fsync::Never,
+                        n => match n.parse() {
+                            Ok(0) | Err(_) => return Err(invalid()),
+                            Ok(n) => Fsync::Every(n),
+                        },
+                    }
+                }
+                "segment-size" => segment_size = value.parse().map_err(|_| invalid())?,
+                _ => {
+                    return Err(EventStoreError::Database(format!(
+                        "Unknown option {}",
+                        key
+                    )))
+                }
+            }
+        }
+
+        Ok((dir, fsync, segment_size))
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Log) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let log = self.0.clone();
+        task::spawn_blocking(move || f(&log)).await?
+    }
+}
+
+#[async_trait]
+impl EventStore for FileEventStore {
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        let sequences = self
+            .run(move |log| log.store(vec![event], expected))
+            .await?;
+        Ok(sequences[0])
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        self.run(move |log| log.store(events, expected)).await
+    }
+
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        let stream = stream.to_string();
+        self.run(move |log| log.append(stream, event, expected))
+            .await
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        match self.run(move |log| log.read(id, 1)).await?.pop() {
+            Some(event) if event.sequence == id => Ok(event),
+            _ => Err(EventStoreError::NoRecord),
+        }
+    }
+
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        self.run(move |log| log.read(from, limit)).await
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        let stream = stream.to_string();
+        self.run(move |log| log.read_stream(&stream, from, limit))
+            .await
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        Ok(self.0.state()?.next - 1)
+    }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .0
+            .state()?
+            .pending
+            .iter()
+            .take(limit as usize)
+            .copied()
+            .collect())
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        let sequences = sequences.to_vec();
+        self.run(move |log| log.acknowledge(&sequences)).await
+    }
+
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        let entries = self.run(move |log| log.read_entries(from, limit)).await?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| Link {
+                sequence: entry.sequence,
+                hash: entry.hash,
+            })
+            .collect())
+    }
+}
+
+impl From<io::Error> for EventStoreError {
+    fn from(error: io::Error) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::process;
+
+    /// An empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("reactrix-log-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn event(i: i64) -> NewEvent {
+        NewEvent {
+            version: 1,
+            type_: "test".to_string(),
+            data: json!({ "i": i }),
+        }
+    }
+
+    /// A log with an event in each of `count` frames
+    fn fill(dir: &Path, fsync: Fsync, segment_size: u64, count: i64) -> Log {
+        let log = Log::open(dir, fsync, segment_size).unwrap();
+        for i in 0..count {
+            log.store(vec![event(i)], None).unwrap();
+        }
+        log
+    }
+
+    fn sequences(log: &Log) -> Vec<i64> {
+        log.read(0, 100)
+            .unwrap()
+            .iter()
+            .map(|event| event.sequence)
+            .collect()
+    }
+
+    fn append_bytes(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn logs_are_read_back() {
+        let dir = scratch("reopen");
+        let log = fill(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE, 2);
+        log.store(vec![event(2), event(3)], Some(1)).unwrap();
+        log.append("s".to_string(), event(4), Some(-1)).unwrap();
+        let hash = log.state().unwrap().last_hash.clone();
+        drop(log);
+
+        let log = Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(sequences(&log), vec![0, 1, 2, 3, 4]);
+        assert_eq!(log.read_stream("s", 0, 10).unwrap()[0].event.sequence, 4);
+        assert_eq!(log.state().unwrap().last_hash, hash);
+        assert!(log.store(vec![event(5)], Some(3)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_writes_are_discarded() {
+        let dir = scratch("torn");
+        let path = segment_path(&dir, 0);
+        drop(fill(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE, 3));
+        let size = fs::metadata(&path).unwrap().len();
+
+        // A partial header, a partial frame, a whole one with a bad checksum
+        // and zeros from a file extended without its data
+        let frames: [&[u8]; 4] = [
+            &[7, 0, 0],
+            &[100, 0, 0, 0, 1, 2, 3, 4, 5],
+            &[2, 0, 0, 0, 1, 2, 3, 4, 5, 6],
+            &[0; 64],
+        ];
+        for frame in &frames {
+            append_bytes(&path, frame);
+            let log = Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).unwrap();
+            assert_eq!(sequences(&log), vec![0, 1, 2]);
+            assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        }
+
+        let log = Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).unwrap();
+        log.store(vec![event(3)], Some(2)).unwrap();
+        drop(log);
+        let log = Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(sequences(&log), vec![0, 1, 2, 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn logs_are_written_by_one_at_a_time() {
+        let dir = scratch("locked");
+        let log = fill(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE, 1);
+        assert!(Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).is_err());
+
+        // Readers don't get in the way, nor can they write
+        let reader = Log::open_read_only(&dir, Fsync::Always).unwrap();
+        assert_eq!(sequences(&reader), vec![0]);
+        assert!(reader.store(vec![event(1)], None).is_err());
+        assert!(reader.acknowledge(&[0]).is_err());
+
+        drop(log);
+        let log = Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).unwrap();
+        log.store(vec![event(1)], Some(0)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn readers_leave_torn_writes_alone() {
+        let dir = scratch("reader");
+        let path = segment_path(&dir, 0);
+        let log = fill(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE, 2);
+
+        // Much like a frame that is still being written
+        append_bytes(&path, &[100, 0, 0, 0, 1, 2, 3, 4, 5]);
+        let size = fs::metadata(&path).unwrap().len();
+
+        let reader = Log::open_read_only(&dir, Fsync::Always).unwrap();
+        assert_eq!(sequences(&reader), vec![0, 1]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+
+        drop(log);
+        assert!(Log::open_read_only(&scratch("missing"), Fsync::Always).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_frames_are_not_discarded() {
+        let dir = scratch("damaged");
+        let path = segment_path(&dir, 0);
+        drop(fill(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE, 3));
+
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_SIZE + 1] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        assert!(Log::open(&dir, Fsync::Always, DEFAULT_SEGMENT_SIZE).is_err());
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lost_tails_of_rolled_segments() {
+        // Every frame gets a segment of its own
+        let dir = scratch("rolled");
+        drop(fill(&dir, Fsync::Always, 1, 3));
+        let path = segment_path(&dir, 1);
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
+
+        assert!(Log::open(&dir, Fsync::Always, 1).is_err());
+
+        // Without fsync, that's to be expected
+        let log = Log::open(&dir, Fsync::Never, 1).unwrap();
+        assert_eq!(sequences(&log), vec![0]);
+        assert!(!segment_path(&dir, 2).exists());
+        assert!(segment_path(&dir, 2).with_extension("0.discarded").exists());
+
+        log.store(vec![event(1)], Some(0)).unwrap();
+        log.store(vec![event(2)], Some(1)).unwrap();
+        drop(log);
+        let log = Log::open(&dir, Fsync::Never, 1).unwrap();
+        assert_eq!(sequences(&log), vec![0, 1, 2]);
+        drop(log);
+
+        // Segments discarded once more don't replace those from before
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
+        drop(Log::open(&dir, Fsync::Never, 1).unwrap());
+        assert!(segment_path(&dir, 2).with_extension("0.discarded").exists());
+        assert!(segment_path(&dir, 2).with_extension("1.discarded").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn published_events_are_not_given_up() {
+        let dir = scratch("published");
+        let log = fill(&dir, Fsync::Never, 1, 3);
+        log.acknowledge(&[0, 1, 2]).unwrap();
+        drop(log);
+
+        let path = segment_path(&dir, 1);
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
+
+        assert!(Log::open(&dir, Fsync::Never, 1).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), size - 1);
+        assert!(segment_path(&dir, 2).exists());
+
+        // Nor when the last segment is gone altogether
+        fs::remove_dir_all(&dir).unwrap();
+        let log = fill(&dir, Fsync::Never, 1, 3);
+        log.acknowledge(&[0, 1, 2]).unwrap();
+        drop(log);
+        fs::remove_file(segment_path(&dir, 2)).unwrap();
+        assert!(Log::open(&dir, Fsync::Never, 1).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lost_segments() {
+        let dir = scratch("lost");
+        drop(fill(&dir, Fsync::Always, 1, 3));
+        fs::remove_file(segment_path(&dir, 1)).unwrap();
+
+        assert!(Log::open(&dir, Fsync::Always, 1).is_err());
+        let log = Log::open(&dir, Fsync::Never, 1).unwrap();
+        assert_eq!(sequences(&log), vec![0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
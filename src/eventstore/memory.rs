@@ -0,0 +1,239 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{
+    chain_hash, EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reactrix::{Event, NewEvent};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+struct Record {
+    version: i32,
+    type_: String,
+    data: Value,
+    timestamp: DateTime<Utc>,
+    hash: Vec<u8>,
+}
+
+impl Record {
+    fn new(event: NewEvent) -> Self {
+        Self {
+            version: event.version,
+            type_: event.type_,
+            data: event.data,
+            timestamp: Utc::now(),
+            hash: Vec::new(),
+        }
+    }
+
+    fn to_event(&self, sequence: i64) -> Event {
+        Event {
+            sequence,
+            version: self.version,
+            type_: self.type_.clone(),
+            data: self.data.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Indexed by sequence
+    events: Vec<Record>,
+    /// Sequences of each stream, indexed by revision
+    streams: HashMap<String, Vec<i64>>,
+    pending: BTreeSet<i64>,
+}
+
+impl State {
+    fn sequence(&self) -> i64 {
+        self.events.len() as i64 - 1
+    }
+
+    fn check(&self, expected: Option<i64>) -> Result<()> {
+        match expected {
+            Some(expected) if expected != self.sequence() => Err(EventStoreError::Conflict),
+            _ => Ok(()),
+        }
+    }
+
+    fn push(&mut self, mut record: Record) -> i64 {
+        let sequence = self.sequence() + 1;
+        let previous = self.events.last().map(|record| record.hash.as_slice());
+        record.hash = chain_hash(previous, &record.to_event(sequence));
+
+        self.events.push(record);
+        self.pending.insert(sequence);
+        sequence
+    }
+}
+
+/// Keeps everything in process memory, for tests and local development
+#[derive(Default)]
+pub struct MemoryEventStore(Mutex<State>);
+
+impl MemoryEventStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.0
+            .lock()
+            .map_err(|e| EventStoreError::Database(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl EventStore for MemoryEventStore {
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        let mut state = self.state()?;
+        state.check(expected)?;
+        Ok(state.push(Record::new(event)))
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        let mut state = self.state()?;
+        state.check(expected)?;
+        Ok(events
+            .into_iter()
+            .map(|event| state.push(Record::new(event)))
+            .collect())
+    }
+
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        let mut state = self.state()?;
+
+        let revision = state.streams.get(stream).map_or(0, Vec::len) as i64;
+        if expected.is_some_and(|expected| expected != revision - 1) {
+            return Err(EventStoreError::Conflict);
+        }
+
+        let sequence = state.push(Record::new(event));
+        state
+            .streams
+            .entry(stream.to_string())
+            .or_default()
+            .push(sequence);
+
+        Ok(StreamPosition { sequence, revision })
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        let state = self.state()?;
+
+        if id < 0 {
+            return Err(EventStoreError::NoRecord);
+        }
+
+        state
+            .events
+            .get(id as usize)
+            .map(|record| record.to_event(id))
+            .ok_or(EventStoreError::NoRecord)
+    }
+
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        let state = self.state()?;
+        let from = from.max(0);
+
+        Ok(state
+            .events
+            .iter()
+            .zip(0..)
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(record, sequence)| record.to_event(sequence))
+            .collect())
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        let state = self.state()?;
+        let from = from.max(0);
+
+        let sequences = match state.streams.get(stream) {
+            Some(sequences) => sequences,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(sequences
+            .iter()
+            .zip(0..)
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(sequence, revision)| StreamEvent {
+                stream: stream.to_string(),
+                revision,
+                event: state.events[*sequence as usize].to_event(*sequence),
+            })
+            .collect())
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        Ok(self.state()?.sequence())
+    }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .state()?
+            .pending
+            .iter()
+            .take(limit as usize)
+            .copied()
+            .collect())
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        let mut state = self.state()?;
+        for sequence in sequences {
+            state.pending.remove(sequence);
+        }
+        Ok(())
+    }
+
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        let state = self.state()?;
+        let from = from.max(0);
+
+        Ok(state
+            .events
+            .iter()
+            .zip(0..)
+            .skip(from as usize)
+            .take(limit as usize)
+            .map(|(record, sequence)| Link {
+                sequence,
+                hash: Some(record.hash.clone()),
+            })
+            .collect())
+    }
+}
@@ -14,86 +14,459 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::eventstore::{EventStore, EventStoreError, Result};
+use crate::eventstore::{
+    chain_hash, EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition,
+};
 
+use async_trait::async_trait;
 use bson::ordered::ValueAccessError;
-use bson::{doc, Bson, DecoderError};
-use chrono::Utc;
-use futures::executor::block_on;
-use mongodb::error::Error as MongoError;
-use mongodb::options::FindOneOptions;
+use bson::spec::BinarySubtype;
+use bson::{doc, Bson, DecoderError, Document};
+use chrono::{TimeZone, Utc};
+use futures::StreamExt;
+use log::warn;
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+use mongodb::options::{FindOneOptions, FindOptions, UpdateOptions};
 use mongodb::Database;
 use reactrix::{Event, NewEvent};
 
+const DUPLICATE_KEY: i32 = 11000;
+const COUNTERS: &str = "counters";
+/// Leaves room for the rest of the counter below the 16 MB document limit
+const MAX_PENDING_SIZE: usize = 15 * 1024 * 1024;
+
 pub struct MongoEventStore(Database);
 
-impl MongoEventStore {
-    pub fn new(database: Database) -> Self {
-        Self(database)
+fn to_event(doc: Document) -> Result<Event> {
+    if doc.contains_key("$err") {
+        return Err(EventStoreError::Database(doc.get_str("$err")?.to_owned()));
     }
+
+    Ok(Event {
+        sequence: doc.get_i64("sequence")?,
+        version: doc.get_i32("version")?,
+        type_: doc.get_str("type")?.to_string(),
+        data: Bson::Document(doc.get_document("data")?.clone()).into(),
+        timestamp: *doc.get_utc_datetime("timestamp")?,
+    })
 }
 
-impl EventStore for MongoEventStore {
-    fn store(&self, event: NewEvent) -> Result<i64> {
-        let sequence = block_on(self.0.collection("counters").find_one_and_update(
-            doc! { "_id": "events" },
-            doc! { "$inc": { "sequence": 1 } },
-            None,
-        ))?
-        .unwrap()
-        .get_i64("sequence")?;
-
-        match bson::to_bson(&event) {
-            Ok(Bson::Document(mut doc)) => {
-                doc.insert("sequence", sequence);
-                doc.insert("timestamp", Utc::now());
-                block_on(self.0.collection("events").insert_one(doc, None))?;
-                Ok(sequence)
-            }
+fn to_document(event: NewEvent) -> Result<Document> {
+    // Events pass through the counter before the collection's validator sees
+    // them, and one that fails there would hold up every later write
+    if event.version < 0 {
+        return Err(EventStoreError::Database(
+            "Event version must not be negative".to_string(),
+        ));
+    }
+
+    match bson::to_bson(&event) {
+        Ok(Bson::Document(ref doc)) if doc.get_document("data").is_err() => Err(
+            EventStoreError::Database("Event data must be an object".to_string()),
+        ),
+        Ok(Bson::Document(mut doc)) => {
+            // BSON keeps milliseconds, the hash must not see more than that
+            doc.insert("timestamp", Utc.timestamp_millis(Utc::now().timestamp_millis()));
+            // Outbox marker, atomic with the event itself
+            doc.insert("published", false);
+            Ok(doc)
+        }
+
+        Ok(_) => Err(EventStoreError::Database(
+            "Could not properly convert JSON to BSON".to_string(),
+        )),
+        Err(e) => Err(EventStoreError::Database(e.to_string())),
+    }
+}
+
+/// Refuse events too big to pass through the counter, before they hold it up
+fn check_size(docs: &[Document]) -> Result<()> {
+    let mut encoded = Vec::new();
+    for doc in docs {
+        bson::encode_document(&mut encoded, doc)
+            .map_err(|e| EventStoreError::Database(e.to_string()))?;
+    }
+
+    if encoded.len() > MAX_PENDING_SIZE {
+        return Err(EventStoreError::TooBig(MAX_PENDING_SIZE as u64));
+    }
+    Ok(())
+}
+
+pub(crate) fn is_duplicate(error: &MongoError) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+impl MongoEventStore {
+    /// Set up the counter and the indexes the store relies on, unless they
+    /// are there already
+    pub async fn open(database: Database) -> Result<Self> {
+        let options = UpdateOptions::builder().upsert(Some(true)).build();
+        match database
+            .collection(COUNTERS)
+            .update_one(
+                doc! { "_id": "events" },
+                doc! { "$setOnInsert": { "sequence": 0i64 } },
+                Some(options),
+            )
+            .await
+        {
+            Ok(_) => {}
+            // Another instance started at the same time
+            Err(ref e) if is_duplicate(e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // Same names as createIndex picks, so that indexes which exist
+        // already are left alone
+        database
+            .run_command(
+                doc! {
+                    "createIndexes": "events",
+                    "indexes": [
+                        {
+                            "key": { "sequence": 1 },
+                            "name": "sequence_1",
+                            "unique": true,
+                        },
+                        {
+                            "key": { "stream": 1, "revision": 1 },
+                            "name": "stream_1_revision_1",
+                            "unique": true,
+                            "partialFilterExpression": { "stream": { "$exists": true } },
+                        },
+                        {
+                            "key": { "published": 1, "sequence": 1 },
+                            "name": "published_1_sequence_1",
+                            "partialFilterExpression": { "published": false },
+                        },
+                    ],
+                },
+                None,
+            )
+            .await?;
+
+        Ok(Self(database))
+    }
 
-            Ok(_) => Err(EventStoreError::Database(
-                "Could not properly convert JSON to BSON".to_string(),
+    async fn counter(&self) -> Result<Document> {
+        match self
+            .0
+            .collection(COUNTERS)
+            .find_one(doc! { "_id": "events" }, None)
+            .await?
+        {
+            Some(counter) => Ok(counter),
+            None => Err(EventStoreError::Database(
+                "Event counter is missing".to_string(),
             )),
-            Err(e) => Err(EventStoreError::Database(e.to_string())),
         }
     }
 
-    fn retrieve(&self, id: i64) -> Result<Event> {
-        match block_on(
-            self.0
-                .collection("events")
-                .find_one(doc! { "sequence": id }, None),
-        ) {
-            Ok(Some(ref doc)) if doc.contains_key(&"$err") => {
-                Err(EventStoreError::Database(doc.get_str(&"$err")?.to_owned()))
+    /// Store the events that went into the counter along with their
+    /// sequences, whether this writer or one that went away put them there
+    async fn settle(&self, next: i64, pending: &[Document]) -> Result<()> {
+        let events = self.0.collection("events");
+        for doc in pending {
+            match events.insert_one(doc.clone(), None).await {
+                Ok(_) => {}
+                // Someone else settled it already
+                Err(ref e) if is_duplicate(e) => {}
+                Err(e) => return Err(e.into()),
             }
-            Ok(Some(doc)) => Ok(Event {
-                sequence: doc.get_i64("sequence")?,
-                version: doc.get_i32("version")?,
-                type_: doc.get_str("type")?.to_string(),
-                data: Bson::Document(doc.get_document("data")?.clone()).into(),
-                timestamp: *doc.get_utc_datetime("timestamp")?,
-            }),
+        }
+
+        self.0
+            .collection(COUNTERS)
+            .update_one(
+                doc! { "_id": "events", "sequence": next },
+                doc! { "$unset": { "pending": "" } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Hand out consecutive sequences to `docs`, chain them to the last event
+    /// and store them, returning the first sequence. The documents go into the
+    /// counter in the same update that hands out their sequences, so from then
+    /// on they are as good as stored: whoever comes across them next finishes
+    /// the job. A stream event gets the stream's next revision. That's why all
+    /// of them together must fit into the counter, see `check_size`.
+    ///
+    /// A transaction would do, but the driver doesn't support them. Instead,
+    /// the single-document update of the counter is the commit point. If a
+    /// writer dies after it, the events stay pending in the counter and no
+    /// one can commit until they are settled. The next writer to read the
+    /// counter inserts them with the sequences they were given and clears
+    /// them, see `settle`. Inserting twice is harmless, the unique index on
+    /// `sequence` turns the second one into a duplicate. Readers don't see
+    /// pending events, but no one sees anything after them either.
+    async fn commit(
+        &self,
+        expected: Option<i64>,
+        stream: Option<(&str, Option<i64>)>,
+        docs: &mut [Document],
+    ) -> Result<i64> {
+        loop {
+            let counter = self.counter().await?;
+            // The counter holds the next sequence to hand out
+            let first = counter.get_i64("sequence")?;
+
+            if counter.contains_key("pending") {
+                let pending = counter
+                    .get_array("pending")?
+                    .iter()
+                    .filter_map(|doc| doc.as_document().cloned())
+                    .collect::<Vec<_>>();
+                self.settle(first, &pending).await?;
+                continue;
+            }
+
+            // With nothing pending, everything before `first` is stored
+            if expected.is_some_and(|expected| expected + 1 != first) {
+                return Err(EventStoreError::Conflict);
+            }
+
+            if let Some((stream, expected)) = stream {
+                let revision = self.revision(stream).await?;
+                if expected.is_some_and(|expected| expected != revision) {
+                    return Err(EventStoreError::Conflict);
+                }
+                for doc in docs.iter_mut() {
+                    doc.insert("revision", revision + 1);
+                }
+            }
+
+            let mut previous = counter.get_binary_generic("hash").ok().cloned();
+            for (doc, sequence) in docs.iter_mut().zip(first..) {
+                doc.insert("sequence", sequence);
+                let hash = chain_hash(previous.as_deref(), &to_event(doc.clone())?);
+                doc.insert("hash", Bson::Binary(BinarySubtype::Generic, hash.clone()));
+                previous = Some(hash);
+            }
+            check_size(docs)?;
+
+            let next = first + docs.len() as i64;
+            let pending = docs.iter().cloned().map(Bson::Document).collect();
+            let mut update = doc! { "sequence": next, "pending": Bson::Array(pending) };
+            if let Some(hash) = previous {
+                update.insert("hash", Bson::Binary(BinarySubtype::Generic, hash));
+            }
+
+            // Only if nobody else got there first; this also makes sure that
+            // the stream's revision is still the latest
+            let filter = doc! {
+                "_id": "events",
+                "sequence": first,
+                "pending": { "$exists": false },
+            };
+            if self
+                .0
+                .collection(COUNTERS)
+                .find_one_and_update(filter, doc! { "$set": Bson::Document(update) }, None)
+                .await?
+                .is_some()
+            {
+                if let Err(e) = self.settle(next, docs).await {
+                    warn!("Events from {} on are stored, but not visible yet: {}", first, e);
+                }
+                return Ok(first);
+            }
+        }
+    }
+
+    async fn revision(&self, stream: &str) -> Result<i64> {
+        let options = FindOneOptions::builder()
+            .sort(Some(doc! { "revision": -1 }))
+            .projection(Some(doc! { "_id": 0, "revision": 1 }))
+            .build();
+
+        match self
+            .0
+            .collection("events")
+            .find_one(doc! { "stream": stream }, Some(options))
+            .await?
+        {
+            Some(doc) => Ok(doc.get_i64("revision")?),
+            None => Ok(-1),
+        }
+    }
+
+    async fn find<T>(
+        &self,
+        filter: Document,
+        options: FindOptions,
+        convert: impl Fn(Document) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        let mut cursor = self
+            .0
+            .collection("events")
+            .find(filter, Some(options))
+            .await?;
+
+        let mut results = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            results.push(convert(doc?)?);
+        }
+
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl EventStore for MongoEventStore {
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        let mut docs = [to_document(event)?];
+        self.commit(expected, None, &mut docs).await
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // All of them go into the counter in one update, so it's all or nothing
+        let mut docs = events
+            .into_iter()
+            .map(to_document)
+            .collect::<Result<Vec<_>>>()?;
+        let first = self.commit(expected, None, &mut docs).await?;
+
+        Ok((first..first + docs.len() as i64).collect())
+    }
+
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        let mut docs = [to_document(event)?];
+        docs[0].insert("stream", stream);
+
+        let sequence = self.commit(None, Some((stream, expected)), &mut docs).await?;
+        Ok(StreamPosition {
+            sequence,
+            revision: docs[0].get_i64("revision")?,
+        })
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        match self
+            .0
+            .collection("events")
+            .find_one(doc! { "sequence": id }, None)
+            .await
+        {
+            Ok(Some(doc)) => to_event(doc),
             Ok(None) => Err(EventStoreError::NoRecord),
             Err(e) => Err(EventStoreError::Database(e.to_string())),
         }
     }
 
-    fn sequence(&self) -> Result<i64> {
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        let options = FindOptions::builder()
+            .sort(Some(doc! { "sequence": 1 }))
+            .limit(Some(limit))
+            .build();
+
+        self.find(doc! { "sequence": { "$gte": from } }, options, to_event)
+            .await
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        let options = FindOptions::builder()
+            .sort(Some(doc! { "revision": 1 }))
+            .limit(Some(limit))
+            .build();
+
+        self.find(
+            doc! { "stream": stream, "revision": { "$gte": from } },
+            options,
+            |doc| {
+                Ok(StreamEvent {
+                    stream: stream.to_string(),
+                    revision: doc.get_i64("revision")?,
+                    event: to_event(doc)?,
+                })
+            },
+        )
+        .await
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        // The highest stored event rather than the counter, which may already
+        // have handed out sequences whose events aren't visible yet
         let options = FindOneOptions::builder()
             .sort(Some(doc! { "sequence": -1 }))
             .projection(Some(doc! { "_id": 0, "sequence": 1 }))
             .build();
 
-        match block_on(self.0.collection("events").find_one(None, Some(options))) {
-            Ok(Some(ref doc)) if doc.contains_key(&"$err") => {
-                Err(EventStoreError::Database(doc.get_str(&"$err")?.to_owned()))
-            }
-            Ok(Some(doc)) => Ok(doc.get_i64(&"sequence")?),
-            Ok(None) => Ok(-1),
-            Err(e) => Err(EventStoreError::Database(e.to_string())),
+        match self
+            .0
+            .collection("events")
+            .find_one(None, Some(options))
+            .await?
+        {
+            Some(doc) => Ok(doc.get_i64("sequence")?),
+            None => Ok(-1),
         }
     }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        let options = FindOptions::builder()
+            .sort(Some(doc! { "sequence": 1 }))
+            .projection(Some(doc! { "_id": 0, "sequence": 1 }))
+            .limit(Some(limit))
+            .build();
+
+        self.find(doc! { "published": false }, options, |doc| {
+            Ok(doc.get_i64("sequence")?)
+        })
+        .await
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        let sequences = sequences
+            .iter()
+            .map(|sequence| Bson::I64(*sequence))
+            .collect::<Vec<_>>();
+
+        self.0
+            .collection("events")
+            .update_many(
+                doc! { "sequence": { "$in": sequences } },
+                doc! { "$unset": { "published": "" } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        let options = FindOptions::builder()
+            .sort(Some(doc! { "sequence": 1 }))
+            .projection(Some(doc! { "_id": 0, "sequence": 1, "hash": 1 }))
+            .limit(Some(limit))
+            .build();
+
+        self.find(doc! { "sequence": { "$gte": from } }, options, |doc| {
+            Ok(Link {
+                sequence: doc.get_i64("sequence")?,
+                hash: doc.get_binary_generic("hash").ok().cloned(),
+            })
+        })
+        .await
+    }
 }
 
 impl From<MongoError> for EventStoreError {
@@ -116,3 +489,35 @@ impl From<DecoderError> for EventStoreError {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn docs(count: usize, size: usize) -> Vec<Document> {
+        (0..count)
+            .map(|_| {
+                to_document(NewEvent {
+                    version: 1,
+                    type_: "test".to_string(),
+                    data: json!({ "padding": "x".repeat(size) }),
+                })
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_must_fit_into_the_counter() {
+        assert!(check_size(&docs(14, 1 << 20)).is_ok());
+        assert!(matches!(
+            check_size(&docs(16, 1 << 20)),
+            Err(EventStoreError::TooBig(_))
+        ));
+        assert!(matches!(
+            check_size(&docs(1, 16 << 20)),
+            Err(EventStoreError::TooBig(_))
+        ));
+    }
+}
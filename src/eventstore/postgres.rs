@@ -14,15 +14,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::eventstore::{EventStore, EventStoreError, Result};
-use crate::PgPool;
+use crate::eventstore::{
+    chain_hash, EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition,
+};
+use crate::schema::events;
+use crate::{blocking, PgPool};
 
+use async_trait::async_trait;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
 use r2d2::Error as R2d2Error;
-use reactrix::{schema, Event, NewEvent};
+use reactrix::{Event, NewEvent};
 use std::sync::Arc;
 
+/// The columns an `Event` is loaded from
+const EVENT: (
+    events::sequence,
+    events::version,
+    events::type_,
+    events::data,
+    events::timestamp,
+) = (
+    events::sequence,
+    events::version,
+    events::type_,
+    events::data,
+    events::timestamp,
+);
+
+/// The columns of a new event, as they are inserted
+fn new_event(
+    event: NewEvent,
+) -> (
+    diesel::dsl::Eq<events::version, i32>,
+    diesel::dsl::Eq<events::type_, String>,
+    diesel::dsl::Eq<events::data, serde_json::Value>,
+) {
+    (
+        events::version.eq(event.version),
+        events::type_.eq(event.type_),
+        events::data.eq(event.data),
+    )
+}
+
 pub struct PostgresEventStore(Arc<PgPool>);
 
 impl PostgresEventStore {
@@ -31,30 +65,266 @@ impl PostgresEventStore {
     }
 }
 
+/// Keep other writers out until we're committed by locking the head of the
+/// hash chain; readers may proceed. Returns the hash of the last event.
+fn lock_head(conn: &PgConnection, expected: Option<i64>) -> Result<Option<Vec<u8>>> {
+    use crate::schema::event_head::dsl;
+
+    let (sequence, hash) = dsl::event_head
+        .select((dsl::sequence, dsl::hash))
+        .for_update()
+        .first::<(i64, Option<Vec<u8>>)>(conn)?;
+
+    match expected {
+        Some(expected) if sequence != expected => Err(EventStoreError::Conflict),
+        _ => Ok(hash),
+    }
+}
+
+/// Chain freshly inserted events to `previous` and move the head past them
+fn link(conn: &PgConnection, mut previous: Option<Vec<u8>>, events: &[Event]) -> Result<()> {
+    use crate::schema::event_head;
+    use crate::schema::events::dsl;
+
+    let last = match events.last() {
+        Some(event) => event.sequence,
+        None => return Ok(()),
+    };
+
+    for event in events {
+        let hash = chain_hash(previous.as_deref(), event);
+        diesel::update(dsl::events.filter(dsl::sequence.eq(event.sequence)))
+            .set(dsl::hash.eq(&hash))
+            .execute(conn)?;
+        previous = Some(hash);
+    }
+
+    diesel::update(event_head::table)
+        .set((event_head::sequence.eq(last), event_head::hash.eq(previous)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Record sequences for the outbox dispatcher within the same transaction
+fn announce(conn: &PgConnection, sequences: &[i64]) -> Result<()> {
+    use crate::schema::outbox::dsl;
+
+    diesel::insert_into(dsl::outbox)
+        .values(
+            sequences
+                .iter()
+                .map(|sequence| dsl::sequence.eq(*sequence))
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    Ok(())
+}
+
+#[async_trait]
 impl EventStore for PostgresEventStore {
-    fn store(&self, event: NewEvent) -> Result<i64> {
-        let result = diesel::insert_into(schema::events::table)
-            .values::<NewEvent>(event)
-            .get_result::<reactrix::Event>(&self.0.get()?)?;
-        Ok(result.sequence)
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        blocking(&self.0, move |conn| {
+            conn.transaction(|| {
+                // Also orders the hash chain
+                let previous = lock_head(conn, expected)?;
+
+                let result = diesel::insert_into(events::table)
+                    .values(new_event(event))
+                    .returning(EVENT)
+                    .get_result::<Event>(conn)?;
+                link(conn, previous, std::slice::from_ref(&result))?;
+                announce(conn, &[result.sequence])?;
+                Ok(result.sequence)
+            })
+        })
+        .await
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        blocking(&self.0, move |conn| {
+            conn.transaction(|| {
+                // Also keeps the sequences of the batch contiguous
+                let previous = lock_head(conn, expected)?;
+
+                let mut events = diesel::insert_into(events::table)
+                    .values(events.into_iter().map(new_event).collect::<Vec<_>>())
+                    .returning(EVENT)
+                    .get_results::<Event>(conn)?;
+                events.sort_by_key(|event| event.sequence);
+                link(conn, previous, &events)?;
+
+                let sequences = events
+                    .iter()
+                    .map(|event| event.sequence)
+                    .collect::<Vec<_>>();
+                announce(conn, &sequences)?;
+                Ok(sequences)
+            })
+        })
+        .await
     }
 
-    fn retrieve(&self, id: i64) -> Result<Event> {
-        use schema::events::dsl;
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        use crate::schema::events::dsl;
+
+        let stream = stream.to_string();
+
+        blocking(&self.0, move |conn| {
+            conn.transaction(|| {
+                // The hash chain serializes appends across streams anyway
+                let previous = lock_head(conn, None)?;
+
+                let revision = dsl::events
+                    .select(diesel::dsl::max(dsl::revision))
+                    .filter(dsl::stream.eq(stream.as_str()))
+                    .first::<Option<i64>>(conn)?
+                    .unwrap_or(-1);
+
+                if expected.is_some_and(|expected| expected != revision) {
+                    return Err(EventStoreError::Conflict);
+                }
+
+                let event = diesel::insert_into(dsl::events)
+                    .values((
+                        dsl::version.eq(event.version),
+                        dsl::type_.eq(event.type_),
+                        dsl::data.eq(event.data),
+                        dsl::stream.eq(stream.as_str()),
+                        dsl::revision.eq(revision + 1),
+                    ))
+                    .returning(EVENT)
+                    .get_result::<Event>(conn)?;
+                link(conn, previous, std::slice::from_ref(&event))?;
+                announce(conn, &[event.sequence])?;
+
+                Ok(StreamPosition {
+                    sequence: event.sequence,
+                    revision: revision + 1,
+                })
+            })
+        })
+        .await
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        use crate::schema::events::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::events
+                .select(EVENT)
+                .filter(dsl::sequence.eq(id))
+                .first::<Event>(conn)?)
+        })
+        .await
+    }
+
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        use crate::schema::events::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::events
+                .select(EVENT)
+                .filter(dsl::sequence.ge(from))
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<Event>(conn)?)
+        })
+        .await
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        use crate::schema::events::dsl;
+
+        let stream = stream.to_string();
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::events
+                .select((dsl::revision, EVENT))
+                .filter(dsl::stream.eq(stream.as_str()))
+                .filter(dsl::revision.ge(from))
+                .order(dsl::revision.asc())
+                .limit(limit)
+                .load::<(Option<i64>, Event)>(conn)?
+                .into_iter()
+                .map(|(revision, event)| StreamEvent {
+                    stream: stream.clone(),
+                    revision: revision.unwrap_or_default(),
+                    event,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        use crate::schema::events::dsl;
+
+        blocking(&self.0, |conn| {
+            Ok(dsl::events
+                .select(dsl::sequence)
+                .order(dsl::sequence.desc())
+                .limit(1)
+                .first::<i64>(conn)
+                .optional()?
+                .unwrap_or(-1))
+        })
+        .await
+    }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        use crate::schema::outbox::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::outbox
+                .select(dsl::sequence)
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<i64>(conn)?)
+        })
+        .await
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        use crate::schema::outbox::dsl;
+
+        let sequences = sequences.to_vec();
 
-        Ok(dsl::events
-            .filter(dsl::sequence.eq(id))
-            .first::<Event>(&self.0.get()?)?)
+        blocking(&self.0, move |conn| {
+            diesel::delete(dsl::outbox.filter(dsl::sequence.eq_any(sequences))).execute(conn)?;
+            Ok(())
+        })
+        .await
     }
 
-    fn sequence(&self) -> Result<i64> {
-        use schema::events::dsl;
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        use crate::schema::events::dsl;
 
-        Ok(dsl::events
-            .select(dsl::sequence)
-            .order(dsl::sequence.desc())
-            .limit(1)
-            .first::<i64>(&self.0.get()?)?)
+        blocking(&self.0, move |conn| {
+            Ok(dsl::events
+                .select((dsl::sequence, dsl::hash))
+                .filter(dsl::sequence.ge(from))
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<(i64, Option<Vec<u8>>)>(conn)?
+                .into_iter()
+                .map(|(sequence, hash)| Link { sequence, hash })
+                .collect())
+        })
+        .await
     }
 }
 
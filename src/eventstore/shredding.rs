@@ -0,0 +1,377 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition};
+use crate::keystore::KeyStore;
+
+use async_trait::async_trait;
+use reactrix::{Event, NewEvent};
+use serde_json::{json, Map, Value};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf::{self as aead, Key, Nonce};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const SUBJECT: &str = "@subject";
+const PERSONAL: &str = "@personal";
+const SEALED: &str = "@sealed";
+const REDACTED: &str = "@redacted";
+
+/// Keys by subject, `None` for those that have been destroyed
+type Keys = HashMap<String, Option<Key>>;
+
+/// The subject of an object marked with `field`, if it is one
+fn subject<'a>(fields: &'a Map<String, Value>, field: &str) -> Option<&'a str> {
+    if fields.len() == 2 && fields.contains_key(field) {
+        fields.get(SUBJECT).and_then(Value::as_str)
+    } else {
+        None
+    }
+}
+
+/// Refuse personal data that isn't marked exactly as documented, rather
+/// than store it unsealed
+fn check_marked(value: &Value) -> Result<()> {
+    match value {
+        Value::Object(fields) if fields.contains_key(PERSONAL) => match subject(fields, PERSONAL) {
+            Some(_) => Ok(()),
+            None => Err(EventStoreError::Invalid(format!(
+                "Personal data must be marked as {{\"{}\": \"…\", \"{}\": …}}",
+                SUBJECT, PERSONAL
+            ))),
+        },
+        Value::Object(fields) => fields.values().try_for_each(check_marked),
+        Value::Array(items) => items.iter().try_for_each(check_marked),
+        _ => Ok(()),
+    }
+}
+
+/// Subjects of all objects marked with `field`
+fn subjects<'a>(value: &'a Value, field: &str, found: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(fields) => match subject(fields, field) {
+            Some(subject) => found.push(subject),
+            None => fields.values().for_each(|v| subjects(v, field, found)),
+        },
+        Value::Array(items) => items.iter().for_each(|v| subjects(v, field, found)),
+        _ => {}
+    }
+}
+
+fn seal(value: &mut Value, keys: &Keys) -> Result<()> {
+    match value {
+        Value::Object(fields) => match subject(fields, PERSONAL).map(str::to_string) {
+            Some(subject) => {
+                let key = match keys.get(&subject) {
+                    Some(Some(key)) => key,
+                    Some(None) => {
+                        *value = json!({ SUBJECT: subject, REDACTED: true });
+                        return Ok(());
+                    }
+                    None => {
+                        return Err(EventStoreError::Database(format!("No key for {}", subject)))
+                    }
+                };
+
+                let plaintext = serde_json::to_vec(&fields[PERSONAL])
+                    .map_err(|e| EventStoreError::Database(e.to_string()))?;
+                let nonce = aead::gen_nonce();
+                let mut sealed = nonce.0.to_vec();
+                sealed.extend(aead::seal(&plaintext, Some(subject.as_bytes()), &nonce, key));
+
+                *value = json!({ SUBJECT: subject, SEALED: hex::encode(sealed) });
+                Ok(())
+            }
+            None => fields.values_mut().try_for_each(|v| seal(v, keys)),
+        },
+        Value::Array(items) => items.iter_mut().try_for_each(|v| seal(v, keys)),
+        _ => Ok(()),
+    }
+}
+
+fn open_sealed(subject: &str, sealed: &Value, key: &Key) -> Option<Value> {
+    let sealed = hex::decode(sealed.as_str()?).ok()?;
+    if sealed.len() < aead::NONCEBYTES {
+        return None;
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(aead::NONCEBYTES);
+    let nonce = Nonce::from_slice(nonce)?;
+    let plaintext = aead::open(ciphertext, Some(subject.as_bytes()), &nonce, key).ok()?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+/// Anything that can't be decrypted anymore reads as redacted
+fn open(value: &mut Value, keys: &Keys) {
+    match value {
+        Value::Object(fields) => match subject(fields, SEALED).map(str::to_string) {
+            Some(subject) => {
+                let opened = match keys.get(&subject) {
+                    Some(Some(key)) => open_sealed(&subject, &fields[SEALED], key),
+                    _ => None,
+                };
+
+                // Marked as it was stored, so that it can be stored again
+                *value = match opened {
+                    Some(personal) => json!({ SUBJECT: subject, PERSONAL: personal }),
+                    None => json!({ SUBJECT: subject, REDACTED: true }),
+                };
+            }
+            None => fields.values_mut().for_each(|v| open(v, keys)),
+        },
+        Value::Array(items) => items.iter_mut().for_each(|v| open(v, keys)),
+        _ => {}
+    }
+}
+
+/// Encrypts the personal data in events with a key of its subject, so that
+/// it can be forgotten by destroying the key while the events stay intact.
+/// Personal data is marked as `{"@subject": "…", "@personal": …}` and
+/// stored as `{"@subject": "…", "@sealed": "…"}`; once the key is gone, it
+/// reads back as `{"@subject": "…", "@redacted": true}`.
+///
+/// Personal data of a subject whose key has been destroyed is stored as
+/// redacted right away, as no new key is issued for it. A seal that fetched
+/// the key just before it was destroyed may still write with it, but what it
+/// writes can't be read back either.
+pub struct ShreddingEventStore {
+    inner: Arc<dyn EventStore>,
+    keys: Arc<dyn KeyStore>,
+}
+
+impl ShreddingEventStore {
+    pub fn new(inner: Arc<dyn EventStore>, keys: Arc<dyn KeyStore>) -> Result<Self> {
+        sodiumoxide::init()
+            .map_err(|_| EventStoreError::Database("Couldn't initialize libsodium".to_string()))?;
+        Ok(Self { inner, keys })
+    }
+
+    /// Look up the keys of the subjects marked with `field` that aren't yet
+    async fn fetch_keys(
+        &self,
+        data: &Value,
+        field: &str,
+        create: bool,
+        keys: &mut Keys,
+    ) -> Result<()> {
+        let mut found = Vec::new();
+        subjects(data, field, &mut found);
+
+        for subject in found {
+            if keys.contains_key(subject) {
+                continue;
+            }
+
+            let key = match self.keys.get(subject).await? {
+                Some(key) => Some(key),
+                None if create => Some(self.keys.insert(subject, &aead::gen_key().0).await?),
+                None => None,
+            };
+            let key = match key {
+                // Tombstone of a destroyed key
+                Some(ref key) if key.is_empty() => None,
+                Some(key) => Some(Key::from_slice(&key).ok_or_else(|| {
+                    EventStoreError::Database(format!("Invalid key for {}", subject))
+                })?),
+                None => None,
+            };
+            keys.insert(subject.to_string(), key);
+        }
+
+        Ok(())
+    }
+
+    async fn seal(&self, mut event: NewEvent) -> Result<NewEvent> {
+        check_marked(&event.data)?;
+        let mut keys = Keys::new();
+        self.fetch_keys(&event.data, PERSONAL, true, &mut keys).await?;
+        if !keys.is_empty() {
+            seal(&mut event.data, &keys)?;
+        }
+        Ok(event)
+    }
+
+    async fn open(&self, mut event: Event, keys: &mut Keys) -> Result<Event> {
+        self.fetch_keys(&event.data, SEALED, false, keys).await?;
+        open(&mut event.data, keys);
+        Ok(event)
+    }
+}
+
+#[async_trait]
+impl EventStore for ShreddingEventStore {
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        let event = self.seal(event).await?;
+        self.inner.store(event, expected).await
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        let mut sealed = Vec::with_capacity(events.len());
+        for event in events {
+            sealed.push(self.seal(event).await?);
+        }
+
+        self.inner.store_batch(sealed, expected).await
+    }
+
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        let event = self.seal(event).await?;
+        self.inner.append(stream, event, expected).await
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        let event = self.inner.retrieve(id).await?;
+        self.open(event, &mut Keys::new()).await
+    }
+
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        // Fetch each key only once per page
+        let mut keys = Keys::new();
+        let mut events = Vec::new();
+        for event in self.inner.retrieve_range(from, limit).await? {
+            events.push(self.open(event, &mut keys).await?);
+        }
+
+        Ok(events)
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        let mut keys = Keys::new();
+        let mut events = Vec::new();
+        for mut event in self.inner.retrieve_stream(stream, from, limit).await? {
+            event.event = self.open(event.event, &mut keys).await?;
+            events.push(event);
+        }
+
+        Ok(events)
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        self.inner.sequence().await
+    }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        self.inner.pending(limit).await
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        self.inner.acknowledge(sequences).await
+    }
+
+    /// The chain covers the sealed data, so it survives shredding
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        self.inner.links(from, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventstore::MemoryEventStore;
+    use crate::keystore::MemoryKeyStore;
+
+    fn event(name: &str) -> NewEvent {
+        NewEvent {
+            version: 1,
+            type_: "test".to_string(),
+            data: json!({ "name": { SUBJECT: "alice", PERSONAL: name } }),
+        }
+    }
+
+    fn setup() -> (
+        Arc<MemoryEventStore>,
+        Arc<MemoryKeyStore>,
+        ShreddingEventStore,
+    ) {
+        let inner = Arc::new(MemoryEventStore::new());
+        let keys = Arc::new(MemoryKeyStore::new());
+        let store = ShreddingEventStore::new(inner.clone(), keys.clone()).unwrap();
+        (inner, keys, store)
+    }
+
+    fn redacted() -> Value {
+        json!({ "name": { SUBJECT: "alice", REDACTED: true } })
+    }
+
+    #[tokio::test]
+    async fn personal_data_is_sealed() {
+        let (inner, _, store) = setup();
+        let sequence = store.store(event("Alice"), None).await.unwrap();
+
+        let stored = inner.retrieve(sequence).await.unwrap();
+        assert!(stored.data["name"][SEALED].is_string());
+        assert!(!stored.data.to_string().contains("Alice"));
+
+        let opened = store.retrieve(sequence).await.unwrap();
+        assert_eq!(opened.data, event("Alice").data);
+    }
+
+    #[tokio::test]
+    async fn malformed_markers_are_refused() {
+        let (inner, _, store) = setup();
+        let malformed = [
+            json!({ "name": { SUBJECT: "alice", PERSONAL: "Alice", "age": 42 } }),
+            json!({ "name": { PERSONAL: "Alice" } }),
+            json!({ "name": { SUBJECT: 42, PERSONAL: "Alice" } }),
+            json!([{ "name": { PERSONAL: "Alice", "other": true } }]),
+        ];
+
+        for data in malformed.iter() {
+            let mut event = event("Alice");
+            event.data = data.clone();
+            assert!(matches!(
+                store.store(event, None).await,
+                Err(EventStoreError::Invalid(_))
+            ));
+        }
+        assert_eq!(inner.sequence().await.unwrap(), -1);
+    }
+
+    #[tokio::test]
+    async fn destroyed_subjects_stay_redacted() {
+        let (inner, keys, store) = setup();
+        let before = store.store(event("Alice"), None).await.unwrap();
+        assert!(keys.destroy("alice").await.unwrap());
+        assert!(!keys.destroy("alice").await.unwrap());
+
+        let after = store.store(event("Alice"), None).await.unwrap();
+        assert_eq!(inner.retrieve(after).await.unwrap().data, redacted());
+        assert_eq!(keys.get("alice").await.unwrap(), Some(Vec::new()));
+
+        for sequence in [before, after] {
+            assert_eq!(store.retrieve(sequence).await.unwrap().data, redacted());
+        }
+    }
+
+    #[tokio::test]
+    async fn seals_racing_a_destroy_are_unreadable() {
+        let (inner, keys, store) = setup();
+        let sealed = store.seal(event("Alice")).await.unwrap();
+        assert!(keys.destroy("alice").await.unwrap());
+
+        let sequence = inner.store(sealed, None).await.unwrap();
+        assert_eq!(store.retrieve(sequence).await.unwrap().data, redacted());
+    }
+}
@@ -0,0 +1,306 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{
+    chain_hash, EventStore, EventStoreError, Link, Result, StreamEvent, StreamPosition,
+};
+use crate::schema::sqlite::{events, outbox};
+use crate::{blocking, SqlitePool};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use reactrix::{Event, NewEvent};
+use std::sync::Arc;
+
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::BigInt);
+
+type Row = (i64, i32, String, String, NaiveDateTime);
+
+pub struct SqliteEventStore(Arc<SqlitePool>);
+
+impl SqliteEventStore {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self(pool)
+    }
+}
+
+fn to_event((sequence, version, type_, data, timestamp): Row) -> Result<Event> {
+    Ok(Event {
+        sequence,
+        version,
+        type_,
+        data: serde_json::from_str(&data).map_err(|e| EventStoreError::Database(e.to_string()))?,
+        timestamp: DateTime::from_utc(timestamp, Utc),
+    })
+}
+
+fn last_sequence(conn: &SqliteConnection) -> Result<i64> {
+    use events::dsl;
+
+    Ok(dsl::events
+        .select(diesel::dsl::max(dsl::sequence))
+        .first::<Option<i64>>(conn)?
+        .unwrap_or(-1))
+}
+
+fn check(conn: &SqliteConnection, expected: Option<i64>) -> Result<()> {
+    match expected {
+        Some(expected) if last_sequence(conn)? != expected => Err(EventStoreError::Conflict),
+        _ => Ok(()),
+    }
+}
+
+/// Hash of the last event before `sequence`, if it has one
+fn previous_hash(conn: &SqliteConnection, sequence: i64) -> Result<Option<Vec<u8>>> {
+    use events::dsl;
+
+    Ok(dsl::events
+        .select(dsl::hash)
+        .filter(dsl::sequence.lt(sequence))
+        .order(dsl::sequence.desc())
+        .first::<Option<Vec<u8>>>(conn)
+        .optional()?
+        .flatten())
+}
+
+/// Insert an event along with its outbox entry; callers provide the transaction
+fn insert(conn: &SqliteConnection, event: NewEvent, stream: Option<(&str, i64)>) -> Result<i64> {
+    use events::dsl;
+
+    let timestamp = Utc::now();
+
+    diesel::insert_into(dsl::events)
+        .values((
+            dsl::version.eq(event.version),
+            dsl::type_.eq(event.type_.as_str()),
+            dsl::data.eq(event.data.to_string()),
+            dsl::timestamp.eq(timestamp.naive_utc()),
+            dsl::stream.eq(stream.map(|(stream, _)| stream)),
+            dsl::revision.eq(stream.map(|(_, revision)| revision)),
+        ))
+        .execute(conn)?;
+
+    let sequence = diesel::select(last_insert_rowid).get_result::<i64>(conn)?;
+
+    // The write lock is ours, so nothing can slip in between
+    let event = Event {
+        sequence,
+        version: event.version,
+        type_: event.type_,
+        data: event.data,
+        timestamp,
+    };
+    let hash = chain_hash(previous_hash(conn, sequence)?.as_deref(), &event);
+    diesel::update(dsl::events.filter(dsl::sequence.eq(sequence)))
+        .set(dsl::hash.eq(hash))
+        .execute(conn)?;
+
+    diesel::insert_into(outbox::table)
+        .values(outbox::sequence.eq(sequence))
+        .execute(conn)?;
+
+    Ok(sequence)
+}
+
+#[async_trait]
+impl EventStore for SqliteEventStore {
+    async fn store(&self, event: NewEvent, expected: Option<i64>) -> Result<i64> {
+        blocking(&self.0, move |conn| {
+            // Immediate transactions take the write lock up front
+            conn.immediate_transaction(|| {
+                check(conn, expected)?;
+                insert(conn, event, None)
+            })
+        })
+        .await
+    }
+
+    async fn store_batch(&self, events: Vec<NewEvent>, expected: Option<i64>) -> Result<Vec<i64>> {
+        blocking(&self.0, move |conn| {
+            conn.immediate_transaction(|| {
+                check(conn, expected)?;
+                events
+                    .into_iter()
+                    .map(|event| insert(conn, event, None))
+                    .collect()
+            })
+        })
+        .await
+    }
+
+    async fn append(
+        &self,
+        stream: &str,
+        event: NewEvent,
+        expected: Option<i64>,
+    ) -> Result<StreamPosition> {
+        use events::dsl;
+
+        let stream = stream.to_string();
+
+        blocking(&self.0, move |conn| {
+            conn.immediate_transaction(|| {
+                let revision = dsl::events
+                    .select(diesel::dsl::max(dsl::revision))
+                    .filter(dsl::stream.eq(stream.as_str()))
+                    .first::<Option<i64>>(conn)?
+                    .unwrap_or(-1);
+
+                if expected.is_some_and(|expected| expected != revision) {
+                    return Err(EventStoreError::Conflict);
+                }
+
+                let sequence = insert(conn, event, Some((stream.as_str(), revision + 1)))?;
+
+                Ok(StreamPosition {
+                    sequence,
+                    revision: revision + 1,
+                })
+            })
+        })
+        .await
+    }
+
+    async fn retrieve(&self, id: i64) -> Result<Event> {
+        use events::dsl;
+
+        blocking(&self.0, move |conn| {
+            to_event(
+                dsl::events
+                    .select((
+                        dsl::sequence,
+                        dsl::version,
+                        dsl::type_,
+                        dsl::data,
+                        dsl::timestamp,
+                    ))
+                    .filter(dsl::sequence.eq(id))
+                    .first::<Row>(conn)?,
+            )
+        })
+        .await
+    }
+
+    async fn retrieve_range(&self, from: i64, limit: i64) -> Result<Vec<Event>> {
+        use events::dsl;
+
+        blocking(&self.0, move |conn| {
+            dsl::events
+                .select((
+                    dsl::sequence,
+                    dsl::version,
+                    dsl::type_,
+                    dsl::data,
+                    dsl::timestamp,
+                ))
+                .filter(dsl::sequence.ge(from))
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<Row>(conn)?
+                .into_iter()
+                .map(to_event)
+                .collect()
+        })
+        .await
+    }
+
+    async fn retrieve_stream(
+        &self,
+        stream: &str,
+        from: i64,
+        limit: i64,
+    ) -> Result<Vec<StreamEvent>> {
+        use events::dsl;
+
+        let stream = stream.to_string();
+
+        blocking(&self.0, move |conn| {
+            dsl::events
+                .select((
+                    dsl::revision,
+                    (
+                        dsl::sequence,
+                        dsl::version,
+                        dsl::type_,
+                        dsl::data,
+                        dsl::timestamp,
+                    ),
+                ))
+                .filter(dsl::stream.eq(stream.as_str()))
+                .filter(dsl::revision.ge(from))
+                .order(dsl::revision.asc())
+                .limit(limit)
+                .load::<(Option<i64>, Row)>(conn)?
+                .into_iter()
+                .map(|(revision, row)| {
+                    Ok(StreamEvent {
+                        stream: stream.clone(),
+                        revision: revision.unwrap_or_default(),
+                        event: to_event(row)?,
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn sequence(&self) -> Result<i64> {
+        blocking(&self.0, last_sequence).await
+    }
+
+    async fn pending(&self, limit: i64) -> Result<Vec<i64>> {
+        use outbox::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::outbox
+                .select(dsl::sequence)
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<i64>(conn)?)
+        })
+        .await
+    }
+
+    async fn acknowledge(&self, sequences: &[i64]) -> Result<()> {
+        use outbox::dsl;
+
+        let sequences = sequences.to_vec();
+
+        blocking(&self.0, move |conn| {
+            diesel::delete(dsl::outbox.filter(dsl::sequence.eq_any(sequences))).execute(conn)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn links(&self, from: i64, limit: i64) -> Result<Vec<Link>> {
+        use events::dsl;
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::events
+                .select((dsl::sequence, dsl::hash))
+                .filter(dsl::sequence.ge(from))
+                .order(dsl::sequence.asc())
+                .limit(limit)
+                .load::<(i64, Option<Vec<u8>>)>(conn)?
+                .into_iter()
+                .map(|(sequence, hash)| Link { sequence, hash })
+                .collect())
+        })
+        .await
+    }
+}
@@ -0,0 +1,298 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::datastore::{is_manifest_key, DataStore};
+use crate::eventstore::EventStore;
+use crate::MAX_PAGE_SIZE;
+
+use chrono::{Duration, Utc};
+use failure::{format_err, Error};
+use log::info;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Length of a Blake2s digest
+const HASH_SIZE: usize = 32;
+
+pub struct Report {
+    pub candidates: usize,
+    pub unreferenced: usize,
+    /// Leftovers of uploads that never finished
+    pub uploads: usize,
+}
+
+/// Collect the strings at a dot-separated path, `*` matching every element
+fn collect<'a>(value: &'a Value, path: &[&str], found: &mut Vec<&'a str>) {
+    let (head, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            if let Value::String(s) = value {
+                found.push(s);
+            }
+            return;
+        }
+    };
+
+    match (value, *head) {
+        (Value::Array(items), "*") => items.iter().for_each(|v| collect(v, rest, found)),
+        (Value::Object(fields), "*") => fields.values().for_each(|v| collect(v, rest, found)),
+        (Value::Array(items), index) => {
+            if let Some(v) = index.parse::<usize>().ok().and_then(|i| items.get(i)) {
+                collect(v, rest, found);
+            }
+        }
+        (Value::Object(fields), key) => {
+            if let Some(v) = fields.get(key) {
+                collect(v, rest, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Blob hashes referenced from events
+async fn mark(events: &dyn EventStore, paths: &[String]) -> Result<HashSet<Vec<u8>>, Error> {
+    let paths = paths
+        .iter()
+        .map(|path| path.split('.').collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut marked = HashSet::new();
+
+    let mut from = 0;
+    loop {
+        let page = events.retrieve_range(from, MAX_PAGE_SIZE).await?;
+        let last = match page.last() {
+            Some(event) => event.sequence,
+            None => break,
+        };
+
+        for event in &page {
+            let mut found = Vec::new();
+            for path in &paths {
+                collect(&event.data, path, &mut found);
+            }
+            marked.extend(
+                found
+                    .into_iter()
+                    .filter_map(|s| hex::decode(s).ok())
+                    .filter(|hash| hash.len() == HASH_SIZE),
+            );
+        }
+
+        from = last + 1;
+    }
+
+    Ok(marked)
+}
+
+/// Add the blobs that marked ones consist of, such as the chunks of a manifest
+async fn trace(data: &dyn DataStore, marked: &mut HashSet<Vec<u8>>) -> Result<(), Error> {
+    let mut queue = marked.iter().cloned().collect::<Vec<_>>();
+    while let Some(hash) = queue.pop() {
+        for reference in data.references(&hash).await? {
+            if marked.insert(reference.clone()) {
+                queue.push(reference);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove blobs older than `grace` that no event refers to, along with what
+/// uploads as old as that left behind. Storing a blob again makes it young
+/// again, so one that an event is about to refer to survives even if it was
+/// a candidate at first.
+pub async fn run(
+    events: &dyn EventStore,
+    data: &dyn DataStore,
+    paths: &[String],
+    grace: Duration,
+    dry_run: bool,
+) -> Result<Report, Error> {
+    let before = Utc::now() - grace;
+    let candidates = data.list(before).await?.into_iter().collect::<HashSet<_>>();
+    let mut marked = mark(events, paths).await?;
+
+    // A day ahead leaves room for the clocks of remote stores
+    let stored = data.list(Utc::now() + Duration::days(1)).await?;
+
+    // Through a store that can't read manifests, every chunk would look
+    // unreferenced
+    for hash in stored.iter().filter(|hash| is_manifest_key(hash)) {
+        if data.references(hash).await?.is_empty() {
+            return Err(format_err!(
+                "Can't trace the chunks of manifest {}",
+                hex::encode(hash)
+            ));
+        }
+    }
+
+    // Young blobs aren't referenced yet, but what they consist of must stay
+    marked.extend(stored.into_iter().filter(|hash| !candidates.contains(hash)));
+    trace(data, &mut marked).await?;
+
+    let mut report = Report {
+        candidates: candidates.len(),
+        unreferenced: 0,
+        uploads: 0,
+    };
+    for hash in candidates.iter().filter(|hash| !marked.contains(*hash)) {
+        if dry_run {
+            info!("Unreferenced blob {}", hex::encode(hash));
+        } else if data.remove(hash, before).await? {
+            info!("Removed unreferenced blob {}", hex::encode(hash));
+        } else {
+            // Stored again in the meantime
+            continue;
+        }
+        report.unreferenced += 1;
+    }
+
+    if !dry_run {
+        report.uploads = data.remove_uploads(before).await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{ChunkedDataStore, DataStoreError, MemoryDataStore};
+    use crate::eventstore::MemoryEventStore;
+    use reactrix::NewEvent;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration as StdDuration;
+
+    async fn refer(events: &MemoryEventStore, hash: &[u8]) {
+        let event = NewEvent {
+            version: 1,
+            type_: "test".to_string(),
+            data: json!({ "blob": hex::encode(hash) }),
+        };
+        events.store(event, None).await.unwrap();
+    }
+
+    /// Candidates and unreferenced blobs of a run over the `blob` fields
+    async fn sweep(
+        events: &MemoryEventStore,
+        data: &dyn DataStore,
+        grace: Duration,
+        dry_run: bool,
+    ) -> (usize, usize) {
+        let paths = vec!["blob".to_string()];
+        let report = run(events, data, &paths, grace, dry_run).await.unwrap();
+        (report.candidates, report.unreferenced)
+    }
+
+    fn is_gone(result: Result<Vec<u8>, DataStoreError>) -> bool {
+        matches!(result, Err(DataStoreError::NoRecord))
+    }
+
+    #[tokio::test]
+    async fn unreferenced_blobs_are_removed() {
+        let events = MemoryEventStore::new();
+        let data = MemoryDataStore::new();
+        let kept = data.store(b"kept").await.unwrap();
+        let dropped = data.store(b"dropped").await.unwrap();
+        refer(&events, &kept).await;
+
+        assert_eq!(sweep(&events, &data, Duration::zero(), true).await, (2, 1));
+        assert!(data.retrieve(&dropped).await.is_ok());
+
+        assert_eq!(sweep(&events, &data, Duration::zero(), false).await, (2, 1));
+        assert_eq!(data.retrieve(&kept).await.unwrap(), b"kept");
+        assert!(is_gone(data.retrieve(&dropped).await));
+    }
+
+    #[tokio::test]
+    async fn referenced_blobs_keep_their_chunks() {
+        let events = MemoryEventStore::new();
+        let data = ChunkedDataStore::new(Arc::new(MemoryDataStore::new()), true);
+        let kept = vec![1; 9 << 20];
+        let dropped = vec![2; 9 << 20];
+        let kept_id = data.store(&kept).await.unwrap();
+        let dropped_id = data.store(&dropped).await.unwrap();
+        refer(&events, &kept_id).await;
+
+        // The manifest and chunks, some of which repeat
+        let references = data.references(&kept_id).await.unwrap();
+        let count = references.into_iter().collect::<HashSet<_>>().len();
+        let swept = sweep(&events, &data, Duration::zero(), false).await;
+        assert_eq!(swept, (2 * count, count));
+        assert_eq!(data.retrieve(&kept_id).await.unwrap(), kept);
+        assert!(is_gone(data.retrieve(&dropped_id).await));
+    }
+
+    #[tokio::test]
+    async fn chunked_blobs_are_kept_without_splitting() {
+        let events = MemoryEventStore::new();
+        let inner = Arc::new(MemoryDataStore::new());
+        let kept = vec![1; 9 << 20];
+        let kept_id = ChunkedDataStore::new(inner.clone(), true)
+            .store(&kept)
+            .await
+            .unwrap();
+        refer(&events, &kept_id).await;
+
+        // Straight on the store, the chunks can't be told from garbage
+        let paths = vec!["blob".to_string()];
+        assert!(run(&events, &*inner, &paths, Duration::zero(), false)
+            .await
+            .is_err());
+
+        let data = ChunkedDataStore::new(inner.clone(), false);
+        assert_eq!(sweep(&events, &data, Duration::zero(), false).await.1, 0);
+        assert_eq!(data.retrieve(&kept_id).await.unwrap(), kept);
+    }
+
+    #[tokio::test]
+    async fn blobs_stored_again_are_not_removed() {
+        let data = MemoryDataStore::new();
+        let hash = data.store(b"again").await.unwrap();
+        let before = Utc::now();
+
+        // Between listing it as old and removing it
+        tokio::time::delay_for(StdDuration::from_millis(10)).await;
+        assert_eq!(data.store(b"again").await.unwrap(), hash);
+        assert!(!data.remove(&hash, before).await.unwrap());
+        assert_eq!(data.retrieve(&hash).await.unwrap(), b"again");
+
+        let later = Utc::now() + Duration::seconds(1);
+        assert!(data.remove(&hash, later).await.unwrap());
+        assert!(is_gone(data.retrieve(&hash).await));
+    }
+
+    #[tokio::test]
+    async fn blobs_stored_again_are_young() {
+        let events = MemoryEventStore::new();
+        let data = ChunkedDataStore::new(Arc::new(MemoryDataStore::new()), true);
+        let again = vec![1; 9 << 20];
+        let again_id = data.store(&again).await.unwrap();
+        let once_id = data.store(b"once").await.unwrap();
+
+        // Wide enough for storing it again to take a while on a busy machine
+        tokio::time::delay_for(StdDuration::from_secs(2)).await;
+        assert_eq!(data.store(&again).await.unwrap(), again_id);
+
+        let grace = Duration::seconds(1);
+        assert_eq!(sweep(&events, &data, grace, false).await, (1, 1));
+        assert_eq!(data.retrieve(&again_id).await.unwrap(), again);
+        assert!(is_gone(data.retrieve(&once_id).await));
+    }
+}
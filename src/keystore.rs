@@ -0,0 +1,49 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod file;
+mod memory;
+mod mongo;
+mod postgres;
+mod sqlite;
+
+use crate::eventstore::Result;
+
+use async_trait::async_trait;
+pub use file::*;
+pub use memory::*;
+pub use mongo::*;
+pub use postgres::*;
+pub use sqlite::*;
+
+/// Keeps the data keys of subjects whose personal data may have to be
+/// forgotten; destroying a key makes everything encrypted with it unreadable.
+/// A destroyed key is kept as an empty tombstone, so that no new key is ever
+/// issued for that subject again.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    /// Keep `key` for `subject` unless there is one already, and return
+    /// whichever it ends up with, also with concurrent calls; that is empty
+    /// if the key of `subject` has been destroyed
+    async fn insert(&self, subject: &str, key: &[u8]) -> Result<Vec<u8>>;
+
+    /// The key of `subject`, empty if it has been destroyed
+    async fn get(&self, subject: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Leave a tombstone in place of the key of `subject`, and return
+    /// `false` if there was no live key to destroy
+    async fn destroy(&self, subject: &str) -> Result<bool>;
+}
@@ -0,0 +1,153 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{EventStoreError, Result};
+use crate::keystore::KeyStore;
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::task;
+use url::Url;
+
+const TEMPORARY: &str = "tmp";
+
+static UNIQUE: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps each key in a file named after the hash of its subject, as
+/// subjects may well be longer than a file name can be
+pub struct FileKeyStore(PathBuf);
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(key) => Ok(Some(key)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Link a complete key file into place, unless there's one already
+fn insert(temporary: &Path, path: &Path, key: &[u8]) -> Result<Vec<u8>> {
+    let result = File::create(temporary)
+        .and_then(|mut file| file.write_all(key).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::hard_link(temporary, path));
+    let _ = fs::remove_file(temporary);
+
+    match result {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    read(path)?.ok_or_else(|| EventStoreError::Database(format!("{:?} vanished", path)))
+}
+
+/// Put an empty tombstone in place of the key file
+fn destroy(temporary: &Path, path: &Path) -> Result<bool> {
+    let destroyed = read(path)?.is_some_and(|key| !key.is_empty());
+    let result = File::create(temporary)
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::rename(temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(temporary);
+    }
+    result?;
+
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(destroyed)
+}
+
+impl FileKeyStore {
+    pub fn new(root: PathBuf) -> Result<Self> {
+        fs::create_dir_all(root.join(TEMPORARY))?;
+        Ok(Self(root))
+    }
+
+    /// Open `keys` in the directory of a `file://` URL, next to the events
+    pub fn open(url: &Url) -> Result<Self> {
+        match url.to_file_path() {
+            Ok(dir) => Self::new(dir.join("keys")),
+            Err(_) => Err(EventStoreError::Database(format!("Invalid path in {}", url))),
+        }
+    }
+
+    fn path(&self, subject: &str) -> PathBuf {
+        self.0
+            .join(hex::encode(Blake2s::digest(subject.as_bytes())))
+    }
+
+    fn temporary(&self) -> PathBuf {
+        self.0.join(TEMPORARY).join(format!(
+            "{}-{}",
+            process::id(),
+            UNIQUE.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+}
+
+#[async_trait]
+impl KeyStore for FileKeyStore {
+    async fn insert(&self, subject: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let temporary = self.temporary();
+        let path = self.path(subject);
+        let key = key.to_vec();
+
+        task::spawn_blocking(move || insert(&temporary, &path, &key)).await?
+    }
+
+    async fn get(&self, subject: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(subject);
+        task::spawn_blocking(move || read(&path)).await?
+    }
+
+    async fn destroy(&self, subject: &str) -> Result<bool> {
+        let temporary = self.temporary();
+        let path = self.path(subject);
+
+        task::spawn_blocking(move || destroy(&temporary, &path)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn long_subjects_are_kept() {
+        let dir = std::env::temp_dir().join(format!("reactrix-keys-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let keys = FileKeyStore::new(dir).unwrap();
+
+        let subject = "x".repeat(1000);
+        assert_eq!(keys.insert(&subject, b"key").await.unwrap(), b"key");
+        assert!(keys.destroy(&subject).await.unwrap());
+        assert_eq!(keys.get(&subject).await.unwrap(), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,61 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{EventStoreError, Result};
+use crate::keystore::KeyStore;
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// Keeps keys in process memory, for tests and local development
+#[derive(Default)]
+pub struct MemoryKeyStore(Mutex<HashMap<String, Vec<u8>>>);
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn keys(&self) -> Result<MutexGuard<'_, HashMap<String, Vec<u8>>>> {
+        self.0
+            .lock()
+            .map_err(|e| EventStoreError::Database(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryKeyStore {
+    async fn insert(&self, subject: &str, key: &[u8]) -> Result<Vec<u8>> {
+        Ok(self
+            .keys()?
+            .entry(subject.to_string())
+            .or_insert_with(|| key.to_vec())
+            .clone())
+    }
+
+    async fn get(&self, subject: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.keys()?.get(subject).cloned())
+    }
+
+    async fn destroy(&self, subject: &str) -> Result<bool> {
+        let mut keys = self.keys()?;
+        let key = keys.entry(subject.to_string()).or_default();
+        let destroyed = !key.is_empty();
+        key.clear();
+        Ok(destroyed)
+    }
+}
@@ -0,0 +1,85 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{is_duplicate, EventStoreError, Result};
+use crate::keystore::KeyStore;
+
+use async_trait::async_trait;
+use bson::doc;
+use bson::spec::BinarySubtype;
+use mongodb::Database;
+
+const KEYS: &str = "keys";
+
+pub struct MongoKeyStore(Database);
+
+impl MongoKeyStore {
+    pub fn new(database: Database) -> Self {
+        Self(database)
+    }
+}
+
+#[async_trait]
+impl KeyStore for MongoKeyStore {
+    async fn insert(&self, subject: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let doc = doc! {
+            "_id": subject,
+            "key": (BinarySubtype::Generic, key.to_owned()),
+        };
+
+        match self.0.collection(KEYS).insert_one(doc, None).await {
+            Ok(_) => Ok(key.to_vec()),
+            Err(ref e) if is_duplicate(e) => self.get(subject).await?.ok_or_else(|| {
+                EventStoreError::Database(format!("Key of {} vanished", subject))
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, subject: &str) -> Result<Option<Vec<u8>>> {
+        match self
+            .0
+            .collection(KEYS)
+            .find_one(doc! { "_id": subject }, None)
+            .await?
+        {
+            Some(doc) => Ok(Some(doc.get_binary_generic("key")?.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    async fn destroy(&self, subject: &str) -> Result<bool> {
+        // Leave the tombstone first, so that a concurrent insert can't slip in after
+        let keys = self.0.collection(KEYS);
+        let tombstone = doc! {
+            "_id": subject,
+            "key": (BinarySubtype::Generic, Vec::new()),
+        };
+        match keys.insert_one(tombstone, None).await {
+            Ok(_) => {}
+            Err(ref e) if is_duplicate(e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let live = doc! {
+            "_id": subject,
+            "key": { "$ne": (BinarySubtype::Generic, Vec::new()) },
+        };
+        let update = doc! { "$set": { "key": (BinarySubtype::Generic, Vec::new()) } };
+        let result = keys.update_one(live, update, None).await?;
+        Ok(result.modified_count > 0)
+    }
+}
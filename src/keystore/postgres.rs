@@ -0,0 +1,88 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::Result;
+use crate::keystore::KeyStore;
+use crate::schema::keys::dsl;
+use crate::{blocking, PgPool};
+
+use async_trait::async_trait;
+use diesel::prelude::*;
+use std::sync::Arc;
+
+const TOMBSTONE: &[u8] = &[];
+
+pub struct PostgresKeyStore(Arc<PgPool>);
+
+impl PostgresKeyStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self(pool)
+    }
+}
+
+#[async_trait]
+impl KeyStore for PostgresKeyStore {
+    async fn insert(&self, subject: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let subject = subject.to_string();
+        let key = key.to_vec();
+
+        blocking(&self.0, move |conn| {
+            diesel::insert_into(dsl::keys)
+                .values((dsl::subject.eq(&subject), dsl::key.eq(key)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            Ok(dsl::keys
+                .select(dsl::key)
+                .filter(dsl::subject.eq(&subject))
+                .first::<Vec<u8>>(conn)?)
+        })
+        .await
+    }
+
+    async fn get(&self, subject: &str) -> Result<Option<Vec<u8>>> {
+        let subject = subject.to_string();
+
+        blocking(&self.0, move |conn| {
+            Ok(dsl::keys
+                .select(dsl::key)
+                .filter(dsl::subject.eq(subject))
+                .first::<Vec<u8>>(conn)
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn destroy(&self, subject: &str) -> Result<bool> {
+        let subject = subject.to_string();
+
+        blocking(&self.0, move |conn| {
+            // Leave the tombstone first, so that a concurrent insert can't slip in after
+            diesel::insert_into(dsl::keys)
+                .values((dsl::subject.eq(&subject), dsl::key.eq(TOMBSTONE)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            let destroyed = diesel::update(dsl::keys)
+                .filter(dsl::subject.eq(&subject))
+                .filter(dsl::key.ne(TOMBSTONE))
+                .set(dsl::key.eq(TOMBSTONE))
+                .execute(conn)?;
+            Ok(destroyed > 0)
+        })
+        .await
+    }
+}
@@ -14,34 +14,97 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Diesel 1.4's derives and `table!` expand to impls newer compilers lint
+#![allow(non_local_definitions, unexpected_cfgs)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+mod auth;
 mod datastore;
 mod eventstore;
+mod gc;
+mod keystore;
+mod merkle;
 mod mq;
+mod outbox;
+mod schema;
+mod sqlite;
+mod tail;
+mod verify;
 
-use bytes::Bytes;
-use datastore::{DataStore, DataStoreError, MongoDataStore, PostgresDataStore};
+use auth::{
+    Denied, FileTokenStore, MongoTokenStore, PostgresTokenStore, SqliteTokenStore, TokenStore,
+};
+use bytes::{Buf, Bytes};
+use datastore::{
+    ByteStream, ChunkedDataStore, DataStore, DataStoreError, EncryptedDataStore, FileDataStore,
+    MemoryDataStore, MongoDataStore, PostgresDataStore, S3DataStore, SqliteDataStore,
+};
 use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::PgConnection;
+use diesel::{Connection, PgConnection, SqliteConnection};
 use dotenv::dotenv;
-use eventstore::{EventStore, EventStoreError, MongoEventStore, PostgresEventStore};
+use eventstore::{
+    EventStore, EventStoreError, FileEventStore, MemoryEventStore, MongoEventStore,
+    PostgresEventStore, ShreddingEventStore, SqliteEventStore,
+};
 use exitfailure::ExitFailure;
 use failure::Fail;
-use log::{error, warn};
-use mongodb::{options::ClientOptions, Client};
+use futures::{Stream, StreamExt, TryStreamExt};
+use hyper::Body;
+use keystore::{
+    FileKeyStore, KeyStore, MemoryKeyStore, MongoKeyStore, PostgresKeyStore, SqliteKeyStore,
+};
+use log::{error, info, warn};
+use merkle::{
+    CheckpointStore, Checkpoints, FileCheckpointStore, MemoryCheckpointStore,
+    MongoCheckpointStore, PostgresCheckpointStore, SqliteCheckpointStore,
+};
+use mongodb::{options::ClientOptions, Client, Database};
 use mq::{Message, PublishMessage, Tx};
 use reactrix::{ApiResult, NewEvent};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
+use std::collections::HashMap;
 use std::env;
+use std::io;
 use std::net::Ipv4Addr;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::Notify;
+use tokio::task::{self, JoinError};
 use url::Url;
-use warp::http::StatusCode;
-use warp::{Filter, Reply};
+use warp::http::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, WWW_AUTHENTICATE,
+};
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
 
 type PgPool = Pool<ConnectionManager<PgConnection>>;
+type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Run Diesel work on the blocking pool so it can't stall the executor
+async fn blocking<C, F, T, E>(pool: &Arc<Pool<ConnectionManager<C>>>, f: F) -> Result<T, E>
+where
+    C: Connection + Send + 'static,
+    F: FnOnce(&C) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<r2d2::Error> + From<JoinError> + Send + 'static,
+{
+    let pool = pool.clone();
+    task::spawn_blocking(move || {
+        let conn = pool.get()?;
+        f(&conn)
+    })
+    .await?
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
 
 #[derive(Debug, Fail)]
 pub enum ReactrixError {
@@ -49,6 +112,8 @@ pub enum ReactrixError {
     Var(String),
     #[fail(display = "Unknown database type {}", 0)]
     UnknownDatabase(String),
+    #[fail(display = "TOKENS_URL is not set, pass --no-auth to serve the API to anyone")]
+    NoTokens,
 }
 
 #[derive(Serialize, Clone)]
@@ -57,6 +122,56 @@ struct Config {
     address: String,
     http_port: u16,
     zmq_port: u16,
+    zmq_replay_port: u16,
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    from: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl RangeQuery {
+    fn limit(&self) -> Option<i64> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+            limit if limit < 1 => None,
+            limit => Some(limit.min(MAX_PAGE_SIZE)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    from: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct CheckpointQuery {
+    from: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Expectation {
+    expected_sequence: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct StreamExpectation {
+    expected_version: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Page<T> {
+    events: Vec<T>,
+    next: i64,
 }
 
 fn error_response(reason: String, status: StatusCode) -> warp::reply::Response {
@@ -67,12 +182,28 @@ fn error_response(reason: String, status: StatusCode) -> warp::reply::Response {
     .into_response()
 }
 
+/// Answer refused requests in the usual shape, leave other rejections to warp
+async fn denied(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    match rejection.find::<Denied>() {
+        Some(denied) => {
+            let mut response = error_response(denied.reason.clone(), denied.status);
+            if denied.status == StatusCode::UNAUTHORIZED {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Ok(response)
+        }
+        None => Err(rejection),
+    }
+}
+
 async fn config_get(config: Config) -> Result<impl Reply, Infallible> {
     Ok(warp::reply::json(&config))
 }
 
 async fn sequence_get(store: Arc<dyn EventStore>) -> Result<impl Reply, Infallible> {
-    match store.sequence() {
+    match store.sequence().await {
         Ok(id) => Ok(warp::reply::json(&ApiResult::Ok { data: id }).into_response()),
         Err(e) => Ok(error_response(
             e.to_string(),
@@ -82,43 +213,152 @@ async fn sequence_get(store: Arc<dyn EventStore>) -> Result<impl Reply, Infallib
 }
 
 async fn event_get(sequence: i64, store: Arc<dyn EventStore>) -> Result<impl Reply, Infallible> {
-    match store.retrieve(sequence) {
+    match store.retrieve(sequence).await {
         Ok(event) => Ok(warp::reply::json(&ApiResult::Ok { data: event }).into_response()),
         Err(EventStoreError::NoRecord) => Ok(error_response(
             "No such event".to_string(),
             StatusCode::NOT_FOUND,
         )),
-        Err(EventStoreError::Database(e)) => Ok(error_response(
+        Err(e) => Ok(error_response(
             e.to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
         )),
     }
 }
 
+fn no_checkpoints() -> warp::reply::Response {
+    error_response("No checkpoints are signed".to_string(), StatusCode::NOT_FOUND)
+}
+
+async fn event_proof_get(
+    sequence: i64,
+    checkpoints: Option<Arc<Checkpoints>>,
+) -> Result<impl Reply, Infallible> {
+    let checkpoints = match checkpoints {
+        Some(checkpoints) => checkpoints,
+        None => return Ok(no_checkpoints()),
+    };
+
+    match checkpoints.proof(sequence).await {
+        Ok(Some(proof)) => Ok(warp::reply::json(&ApiResult::Ok { data: proof }).into_response()),
+        Ok(None) => Ok(error_response(
+            "Event is not covered by a checkpoint".to_string(),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn checkpoint_get(
+    query: CheckpointQuery,
+    checkpoints: Option<Arc<Checkpoints>>,
+) -> Result<impl Reply, Infallible> {
+    let checkpoints = match checkpoints {
+        Some(checkpoints) => checkpoints,
+        None => return Ok(no_checkpoints()),
+    };
+
+    let from = query.from.unwrap_or(0);
+    match checkpoints.consistency(from).await {
+        Ok(Some(consistency)) => {
+            Ok(warp::reply::json(&ApiResult::Ok { data: consistency }).into_response())
+        }
+        Ok(None) => Ok(error_response(
+            format!("No checkpoint covers {} events", from),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn events_get(
+    query: RangeQuery,
+    store: Arc<dyn EventStore>,
+) -> Result<impl Reply, Infallible> {
+    let from = query.from.unwrap_or(0);
+    let limit = match query.limit() {
+        Some(limit) => limit,
+        None => {
+            return Ok(error_response(
+                "Limit must be positive".to_string(),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    match store.retrieve_range(from, limit).await {
+        Ok(events) => {
+            let next = events.last().map_or(from, |event| event.sequence + 1);
+            Ok(warp::reply::json(&ApiResult::Ok {
+                data: Page { events, next },
+            })
+            .into_response())
+        }
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn events_stream(
+    query: StreamQuery,
+    last_event_id: Option<i64>,
+    store: Arc<dyn EventStore>,
+    notifications: Receiver<i64>,
+) -> Result<impl Reply, Infallible> {
+    // A reconnecting client resumes after the last event it has seen
+    let from = last_event_id
+        .map(|sequence| sequence + 1)
+        .or(query.from)
+        .unwrap_or(0);
+
+    let events = tail::tail(store, notifications, from).map(|event| {
+        Ok::<_, Infallible>((
+            warp::sse::id(event.sequence.to_string()),
+            warp::sse::json(event),
+        ))
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+fn created<T: Serialize>(data: T, outbox: &Notify) -> warp::reply::Response {
+    outbox.notify();
+
+    warp::reply::with_status(
+        warp::reply::json(&ApiResult::Ok { data }),
+        StatusCode::CREATED,
+    )
+    .into_response()
+}
+
 async fn event_put(
+    expectation: Expectation,
     event: NewEvent,
     store: Arc<dyn EventStore>,
-    tx: Arc<Mutex<Tx>>,
+    outbox: Arc<Notify>,
 ) -> Result<impl Reply, Infallible> {
-    match store.store(event) {
-        Ok(i) => match tx.lock() {
-            Ok(tx) => match tx.send(PublishMessage::Sequence(i)) {
-                Ok(()) => Ok(warp::reply::with_status(
-                    warp::reply::json(&ApiResult::Ok { data: i }),
-                    StatusCode::CREATED,
-                )
-                .into_response()),
-                Err(e) => {
-                    let message = format!("Created but couldn't notify: {:?}", e);
-                    error!("{}", &message);
-                    Ok(error_response(message, StatusCode::INTERNAL_SERVER_ERROR))
-                }
-            },
-            Err(e) => Ok(error_response(
-                e.to_string(),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )),
-        },
+    match store.store(event, expectation.expected_sequence).await {
+        Ok(i) => Ok(created(i, &outbox)),
+        Err(EventStoreError::Conflict) => Ok(error_response(
+            EventStoreError::Conflict.to_string(),
+            StatusCode::CONFLICT,
+        )),
+        Err(e @ EventStoreError::TooBig(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )),
+        Err(e @ EventStoreError::Invalid(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        )),
         Err(e) => Ok(error_response(
             e.to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -126,18 +366,236 @@ async fn event_put(
     }
 }
 
-async fn data_get(id: String, store: Arc<dyn DataStore>) -> Result<impl warp::Reply, Infallible> {
-    let hash = match hex::decode(id.as_bytes()) {
+async fn events_put(
+    expectation: Expectation,
+    events: Vec<NewEvent>,
+    store: Arc<dyn EventStore>,
+    outbox: Arc<Notify>,
+) -> Result<impl Reply, Infallible> {
+    if events.is_empty() {
+        return Ok(error_response(
+            "Batch must not be empty".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match store.store_batch(events, expectation.expected_sequence).await {
+        Ok(sequences) => Ok(created(sequences, &outbox)),
+        Err(EventStoreError::Conflict) => Ok(error_response(
+            EventStoreError::Conflict.to_string(),
+            StatusCode::CONFLICT,
+        )),
+        Err(e @ EventStoreError::TooBig(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )),
+        Err(e @ EventStoreError::Invalid(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn stream_get(
+    stream: String,
+    query: RangeQuery,
+    store: Arc<dyn EventStore>,
+) -> Result<impl Reply, Infallible> {
+    let from = query.from.unwrap_or(0);
+    let limit = match query.limit() {
+        Some(limit) => limit,
+        None => {
+            return Ok(error_response(
+                "Limit must be positive".to_string(),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    match store.retrieve_stream(&stream, from, limit).await {
+        Ok(events) => {
+            let next = events.last().map_or(from, |event| event.revision + 1);
+            Ok(warp::reply::json(&ApiResult::Ok {
+                data: Page { events, next },
+            })
+            .into_response())
+        }
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn stream_put(
+    stream: String,
+    expectation: StreamExpectation,
+    event: NewEvent,
+    store: Arc<dyn EventStore>,
+    outbox: Arc<Notify>,
+) -> Result<impl Reply, Infallible> {
+    match store.append(&stream, event, expectation.expected_version).await {
+        Ok(position) => Ok(created(position, &outbox)),
+        Err(EventStoreError::Conflict) => Ok(error_response(
+            EventStoreError::Conflict.to_string(),
+            StatusCode::CONFLICT,
+        )),
+        Err(e @ EventStoreError::TooBig(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )),
+        Err(e @ EventStoreError::Invalid(_)) => Ok(error_response(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(e) => Ok(error_response(
+            e.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// What to make of a `Range` header
+enum ByteRange {
+    Whole,
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Only single ranges are supported, anything else gets the whole blob
+fn byte_range(header: &str, size: u64) -> ByteRange {
+    let header = header.trim();
+    if !header.starts_with("bytes=") || header.contains(',') {
+        return ByteRange::Whole;
+    }
+
+    let mut bounds = header["bytes=".len()..].splitn(2, '-');
+    let (first, last) = match (bounds.next(), bounds.next()) {
+        (Some(first), Some(last)) => (first.trim(), last.trim()),
+        _ => return ByteRange::Whole,
+    };
+
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last n bytes
+        (Err(_), Ok(_)) if !first.is_empty() => ByteRange::Whole,
+        (Err(_), Ok(0)) => ByteRange::Unsatisfiable,
+        (Err(_), Ok(_)) if size == 0 => ByteRange::Unsatisfiable,
+        (Err(_), Ok(n)) => ByteRange::Part(size - n.min(size), size - 1),
+        (Ok(first), _) if first >= size => ByteRange::Unsatisfiable,
+        (Ok(first), Err(_)) if last.is_empty() => ByteRange::Part(first, size - 1),
+        (Ok(first), Ok(last)) if first <= last => ByteRange::Part(first, last.min(size - 1)),
+        _ => ByteRange::Whole,
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn decode_hash(id: &str) -> Result<Vec<u8>, warp::reply::Response> {
+    hex::decode(id.as_bytes()).map_err(|e| {
+        let message = format!("Couldn't decode hash: {}", e);
+        warn!("{}", &message);
+        error_response(message, StatusCode::BAD_REQUEST)
+    })
+}
+
+fn blob_response(id: String, data: ByteStream) -> warp::reply::Response {
+    // Too late for a proper error response once the body has started
+    let body = data.map_err(move |e| {
+        error!("Couldn't stream data hash {}: {}", id, e);
+        io::Error::other(e.to_string())
+    });
+
+    let mut response = Response::new(Body::wrap_stream(body));
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response
+}
+
+async fn data_response(
+    id: String,
+    hash: &[u8],
+    range: Option<String>,
+    store: &dyn DataStore,
+) -> Result<warp::reply::Response, DataStoreError> {
+    if let Some(range) = range {
+        let size = store.size(hash).await?;
+
+        match byte_range(&range, size) {
+            ByteRange::Part(first, last) => {
+                let length = last - first + 1;
+                let data = store.retrieve_range(hash, first, length).await?;
+
+                let mut response = blob_response(id, data);
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+                if let Ok(value) = format!("bytes {}-{}/{}", first, last, size).parse() {
+                    headers.insert(CONTENT_RANGE, value);
+                }
+                return Ok(response);
+            }
+
+            ByteRange::Unsatisfiable => {
+                let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+                if let Ok(value) = format!("bytes */{}", size).parse() {
+                    response.headers_mut().insert(CONTENT_RANGE, value);
+                }
+                return Ok(response);
+            }
+
+            ByteRange::Whole => {}
+        }
+    }
+
+    Ok(blob_response(id, store.retrieve_stream(hash).await?))
+}
+
+async fn data_get(
+    id: String,
+    range: Option<String>,
+    store: Arc<dyn DataStore>,
+) -> Result<impl warp::Reply, Infallible> {
+    let hash = match decode_hash(&id) {
         Ok(hash) => hash,
+        Err(response) => return Ok(response),
+    };
+
+    match data_response(id.clone(), &hash, range, store.as_ref()).await {
+        Ok(response) => Ok(response),
+        Err(DataStoreError::NoRecord) => Ok(StatusCode::NOT_FOUND.into_response()),
         Err(e) => {
-            let message = format!("Couldn't decode hash: {}", e);
-            warn!("{}", &message);
-            return Ok(error_response(message, StatusCode::BAD_REQUEST));
+            let message = format!("Couldn't retrieve data hash {}: {}", id, e);
+            error!("{}", &message);
+            Ok(error_response(message, StatusCode::INTERNAL_SERVER_ERROR))
         }
+    }
+}
+
+async fn data_head(id: String, store: Arc<dyn DataStore>) -> Result<impl warp::Reply, Infallible> {
+    let hash = match decode_hash(&id) {
+        Ok(hash) => hash,
+        Err(response) => return Ok(response),
     };
 
-    match store.retrieve(&hash) {
-        Ok(data) => Ok(data.into_response()),
+    match store.size(&hash).await {
+        Ok(size) => {
+            let mut response = StatusCode::OK.into_response();
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            Ok(response)
+        }
         Err(DataStoreError::NoRecord) => Ok(StatusCode::NOT_FOUND.into_response()),
         Err(e) => {
             let message = format!("Couldn't retrieve data hash {}: {}", id, e);
@@ -147,9 +605,43 @@ async fn data_get(id: String, store: Arc<dyn DataStore>) -> Result<impl warp::Re
     }
 }
 
-async fn data_put(bytes: Bytes, store: Arc<dyn DataStore>) -> Result<impl warp::Reply, Infallible> {
-    match store.store(bytes.into_iter().collect::<Vec<u8>>().as_ref()) {
+async fn data_put<S, B>(
+    length: Option<u64>,
+    body: S,
+    limit: u64,
+    store: Arc<dyn DataStore>,
+) -> Result<impl warp::Reply, Infallible>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf + 'static,
+{
+    let too_big = || {
+        let message = format!("Blobs may be up to {} bytes", limit);
+        error_response(message, StatusCode::PAYLOAD_TOO_LARGE)
+    };
+    if length.is_some_and(|length| length > limit) {
+        return Ok(too_big());
+    }
+
+    // Without a length up front, the limit is up to the stream
+    let mut received = 0;
+    let data = body.map(move |chunk| match chunk {
+        Ok(mut chunk) => {
+            received += chunk.remaining() as u64;
+            if received > limit {
+                return Err(DataStoreError::TooBig(limit));
+            }
+            Ok(chunk.to_bytes())
+        }
+        Err(e) => Err(DataStoreError::Database(format!(
+            "Couldn't read body: {}",
+            e
+        ))),
+    });
+
+    match store.store_stream(Box::pin(data)).await {
         Ok(hash) => Ok(hex::encode(hash).into_response()),
+        Err(DataStoreError::TooBig(_)) => Ok(too_big()),
         Err(e) => {
             let message = format!("Couldn't store data: {}", e);
             error!("{}", &message);
@@ -158,6 +650,49 @@ async fn data_put(bytes: Bytes, store: Arc<dyn DataStore>) -> Result<impl warp::
     }
 }
 
+async fn subject_key_delete(
+    subject: String,
+    keys: Arc<dyn KeyStore>,
+) -> Result<impl warp::Reply, Infallible> {
+    match keys.destroy(&subject).await {
+        // Subjects without a key get a tombstone as well, so that none is
+        // ever issued for them
+        Ok(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => {
+            let message = format!("Couldn't destroy key of {}: {}", subject, e);
+            error!("{}", &message);
+            Ok(error_response(message, StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+async fn verify_get(
+    query: VerifyQuery,
+    store: Arc<dyn EventStore>,
+) -> Result<impl warp::Reply, Infallible> {
+    let to = match query.to {
+        Some(to) => to,
+        None => match store.sequence().await {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                return Ok(error_response(
+                    e.to_string(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        },
+    };
+
+    match verify::verify(&*store, query.from.unwrap_or(0), to).await {
+        Ok(report) => Ok(warp::reply::json(&ApiResult::Ok { data: report }).into_response()),
+        Err(e) => {
+            let message = format!("Couldn't verify the event log: {}", e);
+            error!("{}", &message);
+            Ok(error_response(message, StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
 async fn message_post(
     topic: String,
     bytes: Bytes,
@@ -184,7 +719,7 @@ async fn message_post(
 }
 
 fn database_url() -> Result<String, ReactrixError> {
-    env::var("DATABASE_URL").or_else(|_| Err(ReactrixError::Var("DATABASE_URL".to_string())))
+    env::var("DATABASE_URL").map_err(|_| ReactrixError::Var("DATABASE_URL".to_string()))
 }
 
 #[derive(Debug, StructOpt)]
@@ -204,27 +739,219 @@ struct Cli {
     /// ØMQ port to listen on
     #[structopt(long, default_value = "5660")]
     zmq_port: u16,
+
+    /// ØMQ port to serve event replays on
+    #[structopt(long, default_value = "5661")]
+    zmq_replay_port: u16,
+
+    /// Replay events with their personal data decrypted instead of sealed
+    #[structopt(long)]
+    zmq_replay_decrypted: bool,
+
+    /// Split new big blobs into content-defined chunks shared between blobs;
+    /// those stored chunked before are read either way
+    #[structopt(long)]
+    chunked_data: bool,
+
+    /// Refuse blobs that aren't encrypted, once none are left from before
+    /// DATASTORE_KEY was set
+    #[structopt(long)]
+    refuse_plaintext_blobs: bool,
+
+    /// Serve the API without a token store, to anyone who can reach it
+    #[structopt(long)]
+    no_auth: bool,
+
+    /// Refuse blobs bigger than this many bytes; data stores that don't
+    /// stream may have a lower limit of their own
+    #[structopt(long, default_value = "1073741824")]
+    max_blob_size: u64,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-async fn init_stores(url: &str) -> Result<(Arc<dyn EventStore>, Arc<dyn DataStore>), ExitFailure> {
-    match Url::parse(url)?.scheme() {
-        "postgres" => {
-            let pool = Arc::new(Pool::new(ConnectionManager::<PgConnection>::new(url))?);
-            Ok((
-                Arc::new(PostgresEventStore::new(pool.clone())),
-                Arc::new(PostgresDataStore::new(pool)),
-            ))
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Remove blobs that no event refers to
+    Gc {
+        /// Dot-separated path to blob hashes within event data, `*` matches any element
+        #[structopt(long = "path", raw(required = "true", number_of_values = "1"))]
+        paths: Vec<String>,
+
+        /// Keep blobs younger than this many hours
+        #[structopt(long, default_value = "24")]
+        grace_hours: i64,
+
+        /// Only report unreferenced blobs
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Print a new API token, then its hash and scopes for the token store
+    Token {
+        /// Scope to grant, such as events:read or data:write
+        #[structopt(long = "scope", raw(required = "true", number_of_values = "1"))]
+        scopes: Vec<String>,
+    },
+}
+
+/// Connections by URL, so that stores configured with the same one share
+/// a pool instead of each opening their own
+#[derive(Default)]
+struct Connections {
+    postgres: HashMap<String, Arc<PgPool>>,
+    sqlite: HashMap<String, Arc<SqlitePool>>,
+    mongodb: HashMap<String, Database>,
+}
+
+impl Connections {
+    fn postgres(&mut self, url: &str) -> Result<Arc<PgPool>, ExitFailure> {
+        if let Some(pool) = self.postgres.get(url) {
+            return Ok(pool.clone());
         }
-        "mongodb" => {
-            let mut options = ClientOptions::parse(url).await?;
-            options.app_name = Some("reactrix-store".to_string());
-            let client = Client::with_options(options)?;
-            let db = client.database("reactrix");
+
+        let pool = Arc::new(Pool::new(ConnectionManager::<PgConnection>::new(url))?);
+        self.postgres.insert(url.to_string(), pool.clone());
+        Ok(pool)
+    }
+
+    fn sqlite(&mut self, url: &str) -> Result<Arc<SqlitePool>, ExitFailure> {
+        if let Some(pool) = self.sqlite.get(url) {
+            return Ok(pool.clone());
+        }
+
+        let pool = Arc::new(sqlite::connect(url.trim_start_matches("sqlite://"))?);
+        self.sqlite.insert(url.to_string(), pool.clone());
+        Ok(pool)
+    }
+
+    /// Clones of a database share their client and its pool
+    async fn mongodb(&mut self, url: &str) -> Result<Database, ExitFailure> {
+        if let Some(db) = self.mongodb.get(url) {
+            return Ok(db.clone());
+        }
+
+        let mut options = ClientOptions::parse(url).await?;
+        options.app_name = Some("reactrix-store".to_string());
+        let db = Client::with_options(options)?.database("reactrix");
+        self.mongodb.insert(url.to_string(), db.clone());
+        Ok(db)
+    }
+}
+
+/// A file log is opened read-only by those that don't write to it, so that
+/// they can run alongside the process that does
+fn open_file_event_store(url: &Url, read_only: bool) -> Result<Arc<dyn EventStore>, ExitFailure> {
+    if read_only {
+        Ok(Arc::new(FileEventStore::open_read_only(url)?))
+    } else {
+        Ok(Arc::new(FileEventStore::open(url)?))
+    }
+}
+
+/// Both stores from one URL. Blobs go next to the events in a file log.
+async fn init_stores(
+    conns: &mut Connections,
+    url: &str,
+    read_only: bool,
+) -> Result<(Arc<dyn EventStore>, Arc<dyn DataStore>), ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "file" => {
+            let dir = parsed
+                .to_file_path()
+                .map_err(|_| DataStoreError::Database(format!("Invalid path in {}", url)))?;
             Ok((
-                Arc::new(MongoEventStore::new(db.clone())),
-                Arc::new(MongoDataStore::new(db)),
+                open_file_event_store(&parsed, read_only)?,
+                Arc::new(FileDataStore::new(dir.join("blobs"))?),
             ))
         }
+        _ => Ok((
+            init_event_store(conns, url, read_only).await?,
+            init_data_store(conns, url).await?,
+        )),
+    }
+}
+
+async fn init_event_store(
+    conns: &mut Connections,
+    url: &str,
+    read_only: bool,
+) -> Result<Arc<dyn EventStore>, ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "postgres" => Ok(Arc::new(PostgresEventStore::new(conns.postgres(url)?))),
+        "mongodb" => Ok(Arc::new(
+            MongoEventStore::open(conns.mongodb(url).await?).await?,
+        )),
+        "sqlite" => Ok(Arc::new(SqliteEventStore::new(conns.sqlite(url)?))),
+        "file" => open_file_event_store(&parsed, read_only),
+        "memory" => Ok(Arc::new(MemoryEventStore::new())),
+        s => Err(ReactrixError::UnknownDatabase(s.to_string()).into()),
+    }
+}
+
+async fn init_data_store(
+    conns: &mut Connections,
+    url: &str,
+) -> Result<Arc<dyn DataStore>, ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "postgres" => Ok(Arc::new(PostgresDataStore::new(conns.postgres(url)?))),
+        "mongodb" => Ok(Arc::new(MongoDataStore::new(conns.mongodb(url).await?))),
+        "sqlite" => Ok(Arc::new(SqliteDataStore::new(conns.sqlite(url)?))),
+        "file" => Ok(Arc::new(FileDataStore::open(&parsed)?)),
+        "s3" => Ok(Arc::new(S3DataStore::open(&parsed)?)),
+        "memory" => Ok(Arc::new(MemoryDataStore::new())),
+        s => Err(ReactrixError::UnknownDatabase(s.to_string()).into()),
+    }
+}
+
+/// Keys live with the events unless configured otherwise
+async fn init_key_store(
+    conns: &mut Connections,
+    url: &str,
+) -> Result<Arc<dyn KeyStore>, ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "postgres" => Ok(Arc::new(PostgresKeyStore::new(conns.postgres(url)?))),
+        "mongodb" => Ok(Arc::new(MongoKeyStore::new(conns.mongodb(url).await?))),
+        "sqlite" => Ok(Arc::new(SqliteKeyStore::new(conns.sqlite(url)?))),
+        "file" => Ok(Arc::new(FileKeyStore::open(&parsed)?)),
+        "memory" => Ok(Arc::new(MemoryKeyStore::new())),
+        s => Err(ReactrixError::UnknownDatabase(s.to_string()).into()),
+    }
+}
+
+/// Checkpoints live with the events unless configured otherwise
+async fn init_checkpoint_store(
+    conns: &mut Connections,
+    url: &str,
+) -> Result<Arc<dyn CheckpointStore>, ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "postgres" => Ok(Arc::new(PostgresCheckpointStore::new(conns.postgres(url)?))),
+        "mongodb" => Ok(Arc::new(MongoCheckpointStore::new(conns.mongodb(url).await?))),
+        "sqlite" => Ok(Arc::new(SqliteCheckpointStore::new(conns.sqlite(url)?))),
+        "file" => Ok(Arc::new(FileCheckpointStore::open(&parsed)?)),
+        "memory" => Ok(Arc::new(MemoryCheckpointStore::new())),
+        s => Err(ReactrixError::UnknownDatabase(s.to_string()).into()),
+    }
+}
+
+/// Tokens are only checked when there's a store for them
+async fn init_token_store(
+    conns: &mut Connections,
+    url: &str,
+) -> Result<Arc<dyn TokenStore>, ExitFailure> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "postgres" => Ok(Arc::new(PostgresTokenStore::new(conns.postgres(url)?))),
+        "mongodb" => Ok(Arc::new(MongoTokenStore::new(conns.mongodb(url).await?))),
+        "sqlite" => Ok(Arc::new(SqliteTokenStore::new(conns.sqlite(url)?))),
+        "file" => Ok(Arc::new(FileTokenStore::open(&parsed)?)),
         s => Err(ReactrixError::UnknownDatabase(s.to_string()).into()),
     }
 }
@@ -235,14 +962,122 @@ async fn main() -> Result<(), ExitFailure> {
     dotenv()?;
     env_logger::builder().format_timestamp(None).init();
 
-    let url = database_url()?;
-    let (event_store, data_store) = init_stores(&url).await?;
+    if let Some(Command::Token { ref scopes }) = cli.command {
+        let token = auth::generate()?;
+        println!("{}", token);
+        println!("{} {}", hex::encode(auth::hash(&token)), scopes.join(" "));
+        return Ok(());
+    }
+
+    // Collecting garbage only reads events
+    let read_only = matches!(cli.command, Some(Command::Gc { .. }));
+
+    let mut conns = Connections::default();
+
+    // Either store may be configured on its own, DATABASE_URL covers the rest
+    let (event_store, data_store) = match (env::var("EVENTSTORE_URL"), env::var("DATASTORE_URL")) {
+        (Ok(events), Ok(data)) => (
+            init_event_store(&mut conns, &events, read_only).await?,
+            init_data_store(&mut conns, &data).await?,
+        ),
+        (Ok(events), Err(_)) => (
+            init_event_store(&mut conns, &events, read_only).await?,
+            init_data_store(&mut conns, &database_url()?).await?,
+        ),
+        (Err(_), Ok(data)) => (
+            init_event_store(&mut conns, &database_url()?, read_only).await?,
+            init_data_store(&mut conns, &data).await?,
+        ),
+        (Err(_), Err(_)) => init_stores(&mut conns, &database_url()?, read_only).await?,
+    };
+    let key_store = match env::var("KEYSTORE_URL").or_else(|_| env::var("EVENTSTORE_URL")) {
+        Ok(keys) => init_key_store(&mut conns, &keys).await?,
+        Err(_) => init_key_store(&mut conns, &database_url()?).await?,
+    };
+    // The hash chain and the Merkle tree cover events as stored, before any unsealing
+    let chain_store = event_store.clone();
+    let event_store: Arc<dyn EventStore> =
+        Arc::new(ShreddingEventStore::new(event_store, key_store.clone())?);
+
+    // Below the chunking, so that chunks are still shared between blobs
+    let data_store: Arc<dyn DataStore> = match env::var("DATASTORE_KEY") {
+        Ok(key) => Arc::new(EncryptedDataStore::new(
+            data_store,
+            &hex::decode(key)?,
+            !cli.refuse_plaintext_blobs,
+        )?),
+        Err(_) => data_store,
+    };
+    let data_store: Arc<dyn DataStore> =
+        Arc::new(ChunkedDataStore::new(data_store, cli.chunked_data));
+
+    if let Some(Command::Gc {
+        paths,
+        grace_hours,
+        dry_run,
+    }) = cli.command
+    {
+        let grace = chrono::Duration::hours(grace_hours);
+        let report = gc::run(&*event_store, &*data_store, &paths, grace, dry_run).await?;
+        info!(
+            "{} of {} old blobs unreferenced, {} unfinished uploads removed",
+            report.unreferenced, report.candidates, report.uploads
+        );
+        return Ok(());
+    }
+
+    // Without a token store, only ever on request
+    let tokens = match env::var("TOKENS_URL") {
+        Ok(url) => Some(init_token_store(&mut conns, &url).await?),
+        Err(_) if cli.no_auth => {
+            warn!("Authentication is off, anyone who can reach the API may use it");
+            None
+        }
+        Err(_) => return Err(ReactrixError::NoTokens.into()),
+    };
+
+    let replay_store = if cli.zmq_replay_decrypted {
+        event_store.clone()
+    } else {
+        chain_store.clone()
+    };
+    let (tx, notify) = mq::launch(
+        cli.address,
+        cli.zmq_port,
+        cli.zmq_replay_port,
+        replay_store,
+        tokens.clone(),
+    )?;
+    let tx = Arc::new(Mutex::new(tx));
+    let outbox = outbox::launch(event_store.clone(), tx.clone());
+
+    // Without a key, there are no checkpoints to prove anything against
+    let checkpoints = match env::var("CHECKPOINT_KEY") {
+        Ok(key) => {
+            let store = match env::var("CHECKPOINTS_URL").or_else(|_| env::var("EVENTSTORE_URL")) {
+                Ok(url) => init_checkpoint_store(&mut conns, &url).await?,
+                Err(_) => init_checkpoint_store(&mut conns, &database_url()?).await?,
+            };
+            Some(merkle::launch(chain_store.clone(), store, &hex::decode(key)?).await?)
+        }
+        Err(_) => None,
+    };
+
+    // Stores that don't stream hold whole blobs in memory, so less must do
+    let max_blob_size = data_store
+        .max_blob_size()
+        .map_or(cli.max_blob_size, |limit| limit.min(cli.max_blob_size));
 
     let event_store = warp::any().map(move || event_store.clone());
     let data_store = warp::any().map(move || data_store.clone());
-
-    let tx = Arc::new(Mutex::new(mq::launch(cli.address, cli.zmq_port)?));
+    let outbox = warp::any().map(move || outbox.clone());
+    let key_store = warp::any().map(move || key_store.clone());
+    let chain_store = warp::any().map(move || chain_store.clone());
+    let checkpoints = warp::any().map(move || checkpoints.clone());
     let tx = warp::any().map(move || tx.clone());
+    let notifications = warp::any().map(move || notify.subscribe());
+
+    let authorized = move |scope| auth::require(tokens.clone(), scope);
 
     let prefix = warp::path!("v1" / ..);
 
@@ -250,43 +1085,128 @@ async fn main() -> Result<(), ExitFailure> {
         address: cli.address.to_string(),
         http_port: cli.http_port,
         zmq_port: cli.zmq_port,
+        zmq_replay_port: cli.zmq_replay_port,
     };
 
     let config_get = warp::path!("config")
         .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
         .map(move || config.clone())
         .and_then(config_get);
 
     let sequence_get = warp::path!("sequence")
         .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
         .and(event_store.clone())
         .and_then(sequence_get);
 
     let event_get = warp::path!("event" / i64)
         .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
         .and(event_store.clone())
         .and_then(event_get);
 
+    let event_proof_get = warp::path!("event" / i64 / "proof")
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(checkpoints.clone())
+        .and_then(event_proof_get);
+
+    let checkpoint_get = warp::path!("checkpoint")
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(warp::query::<CheckpointQuery>())
+        .and(checkpoints)
+        .and_then(checkpoint_get);
+
+    let events_get = warp::path!("events")
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(warp::query::<RangeQuery>())
+        .and(event_store.clone())
+        .and_then(events_get);
+
+    let events_stream = warp::path!("events" / "stream")
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(warp::query::<StreamQuery>())
+        .and(warp::sse::last_event_id::<i64>())
+        .and(event_store.clone())
+        .and(notifications)
+        .and_then(events_stream);
+
     let event_put = warp::path!("event")
         .and(warp::put())
+        .and(authorized(auth::EVENTS_WRITE))
+        .and(warp::query::<Expectation>())
         .and(warp::body::json())
-        .and(event_store)
-        .and(tx.clone())
+        .and(event_store.clone())
+        .and(outbox.clone())
         .and_then(event_put);
 
+    let events_put = warp::path!("events")
+        .and(warp::put())
+        .and(authorized(auth::EVENTS_WRITE))
+        .and(warp::query::<Expectation>())
+        .and(warp::body::json())
+        .and(event_store.clone())
+        .and(outbox.clone())
+        .and_then(events_put);
+
+    let stream_get = warp::path!("stream" / String)
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(warp::query::<RangeQuery>())
+        .and(event_store.clone())
+        .and_then(stream_get);
+
+    let stream_put = warp::path!("stream" / String)
+        .and(warp::put())
+        .and(authorized(auth::EVENTS_WRITE))
+        .and(warp::query::<StreamExpectation>())
+        .and(warp::body::json())
+        .and(event_store)
+        .and(outbox)
+        .and_then(stream_put);
+
     let data_get = warp::path!("data" / String)
         .and(warp::get())
+        .and(authorized(auth::DATA_READ))
+        .and(warp::header::optional::<String>("range"))
         .and(data_store.clone())
         .and_then(data_get);
 
+    let data_head = warp::path!("data" / String)
+        .and(warp::head())
+        .and(authorized(auth::DATA_READ))
+        .and(data_store.clone())
+        .and_then(data_head);
+
     let data_put = warp::path!("data")
         .and(warp::put())
-        .and(warp::body::bytes())
+        .and(authorized(auth::DATA_WRITE))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and(warp::any().map(move || max_blob_size))
         .and(data_store)
         .and_then(data_put);
 
+    let subject_key_delete = warp::path!("subject" / String / "key")
+        .and(warp::delete())
+        .and(authorized(auth::KEYS_DESTROY))
+        .and(key_store)
+        .and_then(subject_key_delete);
+
+    let verify_get = warp::path!("verify")
+        .and(warp::get())
+        .and(authorized(auth::EVENTS_READ))
+        .and(warp::query::<VerifyQuery>())
+        .and(chain_store)
+        .and_then(verify_get);
+
     let message_post = warp::path!("message" / String)
         .and(warp::post())
+        .and(authorized(auth::MESSAGE_PUBLISH))
         .and(warp::body::bytes())
         .and(tx)
         .and_then(message_post);
@@ -296,11 +1216,22 @@ async fn main() -> Result<(), ExitFailure> {
             config_get
                 .or(sequence_get)
                 .or(event_get)
+                .or(event_proof_get)
+                .or(checkpoint_get)
+                .or(events_get)
+                .or(events_stream)
                 .or(event_put)
+                .or(events_put)
+                .or(stream_get)
+                .or(stream_put)
                 .or(data_get)
+                .or(data_head)
                 .or(data_put)
+                .or(subject_key_delete)
+                .or(verify_get)
                 .or(message_post),
         )
+        .recover(denied)
         .with(warp::log("reactrix"));
 
     warp::serve(api).run((cli.address, cli.http_port)).await;
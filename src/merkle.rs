@@ -0,0 +1,874 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod file;
+mod memory;
+mod mongo;
+mod postgres;
+mod sqlite;
+
+use crate::eventstore::{event_bytes, EventStore, EventStoreError, Result};
+use crate::MAX_PAGE_SIZE;
+
+use async_trait::async_trait;
+use blake2::{Blake2s, Digest};
+use chrono::{DateTime, SecondsFormat, Utc};
+pub use file::*;
+use log::{error, info};
+pub use memory::*;
+pub use mongo::*;
+pub use postgres::*;
+use reactrix::Event;
+use serde::{Deserialize, Serialize, Serializer};
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Seed, Signature};
+pub use sqlite::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+const ORIGIN: &str = "reactrix-store";
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// Leaves per chunk. Only the roots of complete chunks stay in memory, their
+/// leaves are read back from the event store when a proof needs them.
+const CHUNK: usize = 256;
+
+type Hash = [u8; 32];
+
+fn finish(hasher: Blake2s) -> Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+fn leaf_hash(event: &Event) -> Hash {
+    let mut hasher = Blake2s::new();
+    hasher.input([0]);
+    hasher.input(event_bytes(event));
+    finish(hasher)
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Blake2s::new();
+    hasher.input([1]);
+    hasher.input(left);
+    hasher.input(right);
+    finish(hasher)
+}
+
+/// Largest power of two below `n`
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+/// Root over `leaves`
+fn subtree(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => finish(Blake2s::new()),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&subtree(&leaves[..k]), &subtree(&leaves[k..]))
+        }
+    }
+}
+
+/// Leaves of a chunk along with the sequences of their events
+#[derive(Default)]
+struct Chunk {
+    sequences: Vec<i64>,
+    leaves: Vec<Hash>,
+}
+
+/// The chunk that has to be read back before the tree can answer
+#[derive(Debug, PartialEq)]
+struct Missing(usize);
+
+type Answer<T> = std::result::Result<T, Missing>;
+
+/// Merkle tree as in RFC 6962 over the event log in sequence order
+#[derive(Default)]
+struct Tree {
+    /// Sequence to continue reading the log at
+    next: i64,
+    /// Sequence of the first event of each complete chunk
+    starts: Vec<i64>,
+    /// Roots of the complete chunks, then the complete subtrees above them
+    levels: Vec<Vec<Hash>>,
+    /// The chunk still filling up
+    tail: Chunk,
+}
+
+impl Tree {
+    fn size(&self) -> usize {
+        self.starts.len() * CHUNK + self.tail.leaves.len()
+    }
+
+    fn push(&mut self, sequence: i64, leaf: Hash) {
+        self.next = sequence + 1;
+        self.tail.sequences.push(sequence);
+        self.tail.leaves.push(leaf);
+        if self.tail.leaves.len() < CHUNK {
+            return;
+        }
+
+        let chunk = std::mem::take(&mut self.tail);
+        self.starts.push(chunk.sequences[0]);
+
+        let mut node = subtree(&chunk.leaves);
+        for level in 0.. {
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+
+            let nodes = &mut self.levels[level];
+            nodes.push(node);
+            if nodes.len() % 2 == 1 {
+                break;
+            }
+            node = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+        }
+    }
+
+    fn chunk<'a>(&'a self, index: usize, loaded: &'a HashMap<usize, Chunk>) -> Answer<&'a Chunk> {
+        if index == self.starts.len() {
+            Ok(&self.tail)
+        } else {
+            loaded.get(&index).ok_or(Missing(index))
+        }
+    }
+
+    /// Root of `size` leaves from `start` on. Coming from the root, subtrees
+    /// bigger than a chunk are always aligned to chunks.
+    fn hash(&self, start: usize, size: usize, loaded: &HashMap<usize, Chunk>) -> Answer<Hash> {
+        if size >= CHUNK && size.is_power_of_two() && start + size <= self.starts.len() * CHUNK {
+            let height = (size / CHUNK).trailing_zeros() as usize;
+            return Ok(self.levels[height][start / size]);
+        }
+
+        if size > CHUNK {
+            let k = split(size);
+            return Ok(node_hash(
+                &self.hash(start, k, loaded)?,
+                &self.hash(start + k, size - k, loaded)?,
+            ));
+        }
+
+        let chunk = self.chunk(start / CHUNK, loaded)?;
+        let offset = start % CHUNK;
+        Ok(subtree(&chunk.leaves[offset..offset + size]))
+    }
+
+    /// Position of the event with `sequence` among the leaves
+    fn index(&self, sequence: i64, loaded: &HashMap<usize, Chunk>) -> Answer<Option<usize>> {
+        let first = self.tail.sequences.first();
+        let chunk = if first.is_some_and(|first| *first <= sequence) {
+            self.starts.len()
+        } else {
+            match self.starts.binary_search(&sequence) {
+                Ok(chunk) => chunk,
+                Err(0) => return Ok(None),
+                Err(chunk) => chunk - 1,
+            }
+        };
+
+        let sequences = &self.chunk(chunk, loaded)?.sequences;
+        Ok(sequences
+            .binary_search(&sequence)
+            .ok()
+            .map(|offset| chunk * CHUNK + offset))
+    }
+
+    /// Audit path of leaf `index` within `size` leaves from `start` on
+    fn path(
+        &self,
+        index: usize,
+        start: usize,
+        size: usize,
+        loaded: &HashMap<usize, Chunk>,
+        path: &mut Vec<Hash>,
+    ) -> Answer<()> {
+        if size <= 1 {
+            return Ok(());
+        }
+
+        let k = split(size);
+        if index < start + k {
+            self.path(index, start, k, loaded, path)?;
+            path.push(self.hash(start + k, size - k, loaded)?);
+        } else {
+            self.path(index, start + k, size - k, loaded, path)?;
+            path.push(self.hash(start, k, loaded)?);
+        }
+        Ok(())
+    }
+
+    /// Consistency proof of the first `old` leaves within `size` leaves from
+    /// `start` on, SUBPROOF in RFC 6962
+    fn consistency(
+        &self,
+        old: usize,
+        start: usize,
+        size: usize,
+        complete: bool,
+        loaded: &HashMap<usize, Chunk>,
+        proof: &mut Vec<Hash>,
+    ) -> Answer<()> {
+        if old == size {
+            if !complete {
+                proof.push(self.hash(start, size, loaded)?);
+            }
+            return Ok(());
+        }
+
+        let k = split(size);
+        if old <= k {
+            self.consistency(old, start, k, complete, loaded, proof)?;
+            proof.push(self.hash(start + k, size - k, loaded)?);
+        } else {
+            self.consistency(old - k, start + k, size - k, false, loaded, proof)?;
+            proof.push(self.hash(start, k, loaded)?);
+        }
+        Ok(())
+    }
+}
+
+/// A signed tree head
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Checkpoint {
+    pub size: usize,
+    pub root: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    /// Ed25519 public key, to be pinned by whoever checks the signature
+    pub key: String,
+    /// Ed25519 signature over the origin, size, root and timestamp, each
+    /// followed by a newline
+    pub signature: String,
+}
+
+/// The timestamp as it is signed, so that the signature can be checked
+/// against the JSON
+fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn serialize_timestamp<S: Serializer>(
+    timestamp: &DateTime<Utc>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_timestamp(timestamp))
+}
+
+impl Checkpoint {
+    fn body(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n",
+            ORIGIN,
+            self.size,
+            self.root,
+            format_timestamp(&self.timestamp)
+        )
+    }
+
+    fn verify(&self, public: &PublicKey) -> bool {
+        let signature = match hex::decode(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        self.key == hex::encode(&public[..])
+            && Signature::from_slice(&signature).is_some_and(|signature| {
+                sign::verify_detached(&signature, self.body().as_bytes(), public)
+            })
+    }
+}
+
+/// Keeps the signed checkpoints, so that the tree can be checked against the
+/// latest one after a restart
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    async fn add(&self, checkpoint: &Checkpoint) -> Result<()>;
+    async fn latest(&self) -> Result<Option<Checkpoint>>;
+}
+
+#[derive(Serialize)]
+pub struct Proof {
+    pub sequence: i64,
+    /// Position of the event among the leaves
+    pub index: usize,
+    pub leaf: String,
+    /// Sibling hashes from the leaf up to the root
+    pub path: Vec<String>,
+    pub checkpoint: Checkpoint,
+}
+
+/// Proof that the latest checkpoint extends the tree of an earlier size
+#[derive(Serialize)]
+pub struct Consistency {
+    pub from: usize,
+    pub proof: Vec<String>,
+    pub checkpoint: Checkpoint,
+}
+
+#[derive(Default)]
+struct State {
+    tree: Tree,
+    checkpoint: Option<Checkpoint>,
+    /// Why the log can't be vouched for anymore, if it can't
+    alarm: Option<String>,
+}
+
+/// Merkle tree over the event log in sequence order, along with its latest
+/// checkpoint. Leaves hash events as stored, sealed personal data included.
+pub struct Checkpoints {
+    state: Mutex<State>,
+    events: Arc<dyn EventStore>,
+    store: Arc<dyn CheckpointStore>,
+    public: PublicKey,
+    secret: SecretKey,
+}
+
+impl Checkpoints {
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|e| EventStoreError::Database(e.to_string()))
+    }
+
+    /// Stop vouching for the log
+    fn alarm(&self, reason: String) -> EventStoreError {
+        error!("{}", &reason);
+        if let Ok(mut state) = self.state() {
+            state.alarm = Some(reason.clone());
+        }
+        EventStoreError::Database(reason)
+    }
+
+    /// Leaves of the chunk starting at `start`, read back from the log
+    async fn read(&self, start: i64) -> Result<Chunk> {
+        let mut chunk = Chunk::default();
+        let mut next = start;
+
+        while chunk.leaves.len() < CHUNK {
+            let limit = ((CHUNK - chunk.leaves.len()) as i64).min(MAX_PAGE_SIZE);
+            let page = self.events.retrieve_range(next, limit).await?;
+            next = match page.last() {
+                Some(event) => event.sequence + 1,
+                None => break,
+            };
+
+            for event in page {
+                chunk.sequences.push(event.sequence);
+                chunk.leaves.push(leaf_hash(&event));
+            }
+        }
+
+        Ok(chunk)
+    }
+
+    /// Answer `query` from the tree, reading back the chunks it needs. These
+    /// have to match the roots kept for them, else the log was rewritten.
+    async fn query<T>(
+        &self,
+        query: impl Fn(&State, &HashMap<usize, Chunk>) -> Answer<Result<T>>,
+    ) -> Result<T> {
+        let mut loaded = HashMap::new();
+
+        loop {
+            let (index, start, root) = {
+                let state = self.state()?;
+                if let Some(ref alarm) = state.alarm {
+                    return Err(EventStoreError::Database(alarm.clone()));
+                }
+
+                match query(&state, &loaded) {
+                    Ok(result) => return result,
+                    Err(Missing(index)) => (
+                        index,
+                        state.tree.starts[index],
+                        state.tree.levels[0][index],
+                    ),
+                }
+            };
+
+            let chunk = self.read(start).await?;
+            if subtree(&chunk.leaves) != root {
+                return Err(self.alarm(format!(
+                    "Events from {} on changed after they were added to the Merkle tree",
+                    start
+                )));
+            }
+            loaded.insert(index, chunk);
+        }
+    }
+
+    /// Inclusion proof of an event against the latest checkpoint, nothing if
+    /// the checkpoint doesn't cover it
+    pub async fn proof(&self, sequence: i64) -> Result<Option<Proof>> {
+        self.query(|state, loaded| {
+            let checkpoint = match state.checkpoint {
+                Some(ref checkpoint) => checkpoint,
+                None => return Ok(Ok(None)),
+            };
+
+            let index = match state.tree.index(sequence, loaded)? {
+                Some(index) if index < checkpoint.size => index,
+                _ => return Ok(Ok(None)),
+            };
+
+            let mut path = Vec::new();
+            state.tree.path(index, 0, checkpoint.size, loaded, &mut path)?;
+            let leaf = state.tree.hash(index, 1, loaded)?;
+
+            Ok(Ok(Some(Proof {
+                sequence,
+                index,
+                leaf: hex::encode(leaf),
+                path: path.iter().map(hex::encode).collect(),
+                checkpoint: checkpoint.clone(),
+            })))
+        })
+        .await
+    }
+
+    /// Consistency proof of the latest checkpoint with the tree of `from`
+    /// leaves, nothing if there is no checkpoint that big
+    pub async fn consistency(&self, from: usize) -> Result<Option<Consistency>> {
+        self.query(|state, loaded| {
+            let checkpoint = match state.checkpoint {
+                Some(ref checkpoint) if checkpoint.size >= from => checkpoint,
+                _ => return Ok(Ok(None)),
+            };
+
+            let mut proof = Vec::new();
+            if from > 0 {
+                state
+                    .tree
+                    .consistency(from, 0, checkpoint.size, true, loaded, &mut proof)?;
+            }
+
+            Ok(Ok(Some(Consistency {
+                from,
+                proof: proof.iter().map(hex::encode).collect(),
+                checkpoint: checkpoint.clone(),
+            })))
+        })
+        .await
+    }
+
+    /// Add the events that aren't in the tree yet. As events become visible
+    /// in order of sequence, a gap in the sequences is there for good; an
+    /// event showing up in one later changes the tree below a checkpoint,
+    /// which the check after a restart and reading chunks back both catch.
+    async fn grow(&self) -> Result<()> {
+        loop {
+            let next = self.state()?.tree.next;
+            let page = self.events.retrieve_range(next, MAX_PAGE_SIZE).await?;
+            let leaves = page
+                .iter()
+                .map(|event| (event.sequence, leaf_hash(event)))
+                .collect::<Vec<_>>();
+
+            let mut state = self.state()?;
+            for (sequence, leaf) in leaves {
+                state.tree.push(sequence, leaf);
+            }
+
+            if page.len() < MAX_PAGE_SIZE as usize {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Make sure the log still extends the latest stored checkpoint, and
+    /// carry on from there
+    async fn resume(&self) -> Result<()> {
+        let latest = match self.store.latest().await? {
+            Some(latest) => latest,
+            None => return Ok(()),
+        };
+
+        if !latest.verify(&self.public) {
+            return Err(self.alarm(format!(
+                "The checkpoint of {} events isn't signed with the checkpoint key",
+                latest.size
+            )));
+        }
+
+        let size = self.state()?.tree.size();
+        if size < latest.size {
+            return Err(self.alarm(format!(
+                "The event log has {} events, fewer than the checkpoint of {}",
+                size, latest.size
+            )));
+        }
+
+        let root = self
+            .query(|state, loaded| Ok(Ok(state.tree.hash(0, latest.size, loaded)?)))
+            .await?;
+        if hex::encode(root) != latest.root {
+            return Err(self.alarm(format!(
+                "The event log doesn't extend the checkpoint of {} events",
+                latest.size
+            )));
+        }
+
+        info!("Event log extends the checkpoint of {} events", latest.size);
+        self.state()?.checkpoint = Some(latest);
+        Ok(())
+    }
+
+    /// Sign the current tree, unless the latest checkpoint already covers it.
+    /// The checkpoint is stored before anyone gets to see it.
+    async fn sign(&self) -> Result<()> {
+        let checkpoint = self
+            .query(|state, loaded| {
+                let size = state.tree.size();
+                if state.checkpoint.as_ref().is_some_and(|c| c.size == size) {
+                    return Ok(Ok(None));
+                }
+
+                let mut checkpoint = Checkpoint {
+                    size,
+                    root: hex::encode(state.tree.hash(0, size, loaded)?),
+                    timestamp: Utc::now(),
+                    key: hex::encode(&self.public[..]),
+                    signature: String::new(),
+                };
+                let signature = sign::sign_detached(checkpoint.body().as_bytes(), &self.secret);
+                checkpoint.signature = hex::encode(&signature[..]);
+                Ok(Ok(Some(checkpoint)))
+            })
+            .await?;
+
+        if let Some(checkpoint) = checkpoint {
+            self.store.add(&checkpoint).await?;
+            info!("Signed checkpoint of {} events", checkpoint.size);
+            self.state()?.checkpoint = Some(checkpoint);
+        }
+        Ok(())
+    }
+}
+
+async fn run(checkpoints: Arc<Checkpoints>) {
+    loop {
+        delay_for(CHECKPOINT_INTERVAL).await;
+
+        let result = match checkpoints.grow().await {
+            Ok(()) => checkpoints.sign().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Couldn't update checkpoint: {}", e);
+        }
+    }
+}
+
+/// Build the tree over `events`, check it against the latest checkpoint in
+/// `store` and keep signing checkpoints with the Ed25519 key derived from
+/// `seed`. If the log doesn't extend the latest checkpoint, nothing gets
+/// signed anymore and proofs fail with the reason.
+pub async fn launch(
+    events: Arc<dyn EventStore>,
+    store: Arc<dyn CheckpointStore>,
+    seed: &[u8],
+) -> Result<Arc<Checkpoints>> {
+    sodiumoxide::init()
+        .map_err(|_| EventStoreError::Database("Couldn't initialize libsodium".to_string()))?;
+
+    let seed = Seed::from_slice(seed).ok_or_else(|| {
+        EventStoreError::Database(format!(
+            "Checkpoint key must be {} bytes long",
+            sign::SEEDBYTES
+        ))
+    })?;
+    let (public, secret) = sign::keypair_from_seed(&seed);
+
+    let checkpoints = Arc::new(Checkpoints {
+        state: Mutex::new(State::default()),
+        events,
+        store,
+        public,
+        secret,
+    });
+
+    checkpoints.grow().await?;
+    // An alarm is raised and kept, the server still has to come up
+    let result = match checkpoints.resume().await {
+        Ok(()) => checkpoints.sign().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Couldn't update checkpoint: {}", e);
+    }
+
+    tokio::spawn(run(checkpoints.clone()));
+    Ok(checkpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventstore::MemoryEventStore;
+    use reactrix::NewEvent;
+    use serde_json::json;
+
+    fn leaf(i: usize) -> Hash {
+        let mut hasher = Blake2s::new();
+        hasher.input(i.to_le_bytes());
+        finish(hasher)
+    }
+
+    /// A tree over `size` leaves for every other sequence, along with all
+    /// complete chunks as if read back
+    fn tree(size: usize) -> (Tree, Vec<Hash>, HashMap<usize, Chunk>) {
+        let leaves = (0..size).map(leaf).collect::<Vec<_>>();
+        let mut tree = Tree::default();
+        for (i, leaf) in leaves.iter().enumerate() {
+            tree.push(2 * i as i64, *leaf);
+        }
+
+        let mut loaded = HashMap::new();
+        for (index, chunk) in leaves.chunks(CHUNK).enumerate() {
+            if chunk.len() == CHUNK {
+                let sequences = (0..CHUNK).map(|i| 2 * (index * CHUNK + i) as i64).collect();
+                loaded.insert(
+                    index,
+                    Chunk {
+                        sequences,
+                        leaves: chunk.to_vec(),
+                    },
+                );
+            }
+        }
+
+        (tree, leaves, loaded)
+    }
+
+    /// Audit path verification from RFC 9162, 2.1.3.2
+    fn verify_inclusion(index: usize, size: usize, leaf: Hash, path: &[Hash], root: Hash) -> bool {
+        if index >= size {
+            return false;
+        }
+
+        let (mut f, mut s, mut r) = (index, size - 1, leaf);
+        for p in path {
+            if s == 0 {
+                return false;
+            }
+
+            if f & 1 == 1 || f == s {
+                r = node_hash(p, &r);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                r = node_hash(&r, p);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+
+        s == 0 && r == root
+    }
+
+    /// Consistency proof verification from RFC 9162, 2.1.4.2
+    fn verify_consistency(
+        old: usize,
+        size: usize,
+        first: Hash,
+        second: Hash,
+        proof: &[Hash],
+    ) -> bool {
+        if old == size {
+            return proof.is_empty() && first == second;
+        }
+
+        let mut path = proof.to_vec();
+        if old.is_power_of_two() {
+            path.insert(0, first);
+        }
+        if path.is_empty() {
+            return false;
+        }
+
+        let (mut f, mut s) = (old - 1, size - 1);
+        while f & 1 == 1 {
+            f >>= 1;
+            s >>= 1;
+        }
+
+        let (mut fr, mut sr) = (path[0], path[0]);
+        for c in &path[1..] {
+            if s == 0 {
+                return false;
+            }
+
+            if f & 1 == 1 || f == s {
+                fr = node_hash(c, &fr);
+                sr = node_hash(c, &sr);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                sr = node_hash(&sr, c);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+
+        fr == first && sr == second && s == 0
+    }
+
+    const SIZES: &[usize] = &[1, 2, 3, 7, 8, 255, 256, 257, 511, 512, 513, 768, 1000];
+
+    #[test]
+    fn roots_match_the_definition() {
+        let (tree, leaves, loaded) = tree(1000);
+        assert_eq!(tree.size(), 1000);
+
+        for size in 0..=1000 {
+            assert_eq!(tree.hash(0, size, &loaded), Ok(subtree(&leaves[..size])));
+        }
+    }
+
+    #[test]
+    fn roots_need_no_chunks() {
+        for &size in SIZES {
+            let (tree, leaves, _) = tree(size);
+            assert_eq!(tree.hash(0, size, &HashMap::new()), Ok(subtree(&leaves)));
+        }
+    }
+
+    #[test]
+    fn complete_chunks_are_read_back() {
+        let (tree, _, _) = tree(600);
+        assert_eq!(tree.hash(300, 1, &HashMap::new()), Err(Missing(1)));
+        assert_eq!(tree.index(700, &HashMap::new()), Err(Missing(1)));
+        assert!(tree.hash(520, 1, &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn events_are_found_by_sequence() {
+        let (tree, _, loaded) = tree(600);
+        assert_eq!(tree.index(0, &loaded), Ok(Some(0)));
+        assert_eq!(tree.index(1, &loaded), Ok(None));
+        assert_eq!(tree.index(2 * 256, &loaded), Ok(Some(256)));
+        assert_eq!(tree.index(2 * 599, &loaded), Ok(Some(599)));
+        assert_eq!(tree.index(2 * 600, &loaded), Ok(None));
+        assert_eq!(tree.index(-1, &loaded), Ok(None));
+    }
+
+    #[test]
+    fn audit_paths_verify() {
+        let (tree, leaves, loaded) = tree(1000);
+
+        for &size in SIZES {
+            let root = subtree(&leaves[..size]);
+            for (index, &hash) in leaves[..size].iter().enumerate() {
+                let mut path = Vec::new();
+                tree.path(index, 0, size, &loaded, &mut path).unwrap();
+                assert!(verify_inclusion(index, size, hash, &path, root));
+                assert!(!verify_inclusion(index, size, leaf(size), &path, root));
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_verify() {
+        let (tree, leaves, loaded) = tree(1000);
+
+        for &size in SIZES {
+            for &old in SIZES.iter().filter(|&&old| old <= size) {
+                let mut proof = Vec::new();
+                tree.consistency(old, 0, size, true, &loaded, &mut proof).unwrap();
+
+                let first = subtree(&leaves[..old]);
+                let second = subtree(&leaves[..size]);
+                assert!(verify_consistency(old, size, first, second, &proof));
+                if old < size {
+                    assert!(!verify_consistency(old, size, leaf(size), second, &proof));
+                }
+            }
+        }
+    }
+
+    async fn fill(store: &MemoryEventStore, count: usize) {
+        for i in 0..count {
+            let event = NewEvent {
+                version: 1,
+                type_: "test".to_string(),
+                data: json!({ "i": i }),
+            };
+            store.store(event, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn checkpoints_survive_restarts() {
+        let events = Arc::new(MemoryEventStore::new());
+        let store = Arc::new(MemoryCheckpointStore::new());
+        let seed = [7; 32];
+
+        fill(&events, 300).await;
+        let checkpoints = launch(events.clone(), store.clone(), &seed).await.unwrap();
+        let proof = checkpoints.proof(123).await.unwrap().unwrap();
+        assert_eq!(proof.checkpoint.size, 300);
+
+        fill(&events, 20).await;
+        let checkpoints = launch(events.clone(), store.clone(), &seed).await.unwrap();
+        let consistency = checkpoints.consistency(300).await.unwrap().unwrap();
+        assert_eq!(consistency.checkpoint.size, 320);
+        assert!(!consistency.proof.is_empty());
+
+        // Another log of the same size doesn't extend the checkpoint
+        let rewritten = Arc::new(MemoryEventStore::new());
+        fill(&rewritten, 320).await;
+        let checkpoints = launch(rewritten, store.clone(), &seed).await.unwrap();
+        assert!(checkpoints.proof(123).await.is_err());
+        assert!(checkpoints.consistency(0).await.is_err());
+
+        // Neither does it check out with another key
+        let checkpoints = launch(events, store, &[8; 32]).await.unwrap();
+        assert!(checkpoints.proof(123).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn signatures_check_out_against_the_json() {
+        let events = Arc::new(MemoryEventStore::new());
+        let store = Arc::new(MemoryCheckpointStore::new());
+        let seed = [7; 32];
+
+        fill(&events, 10).await;
+        let checkpoints = launch(events, store, &seed).await.unwrap();
+        let checkpoint = checkpoints.proof(0).await.unwrap().unwrap().checkpoint;
+
+        let json = serde_json::to_value(&checkpoint).unwrap();
+        let timestamp = json["timestamp"].as_str().unwrap();
+        assert!(checkpoint.body().ends_with(&format!("\n{}\n", timestamp)));
+
+        let (public, _) = sign::keypair_from_seed(&Seed(seed));
+        let parsed: Checkpoint = serde_json::from_value(json).unwrap();
+        assert!(parsed.verify(&public));
+    }
+}
@@ -0,0 +1,99 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{EventStoreError, Result};
+use crate::merkle::{Checkpoint, CheckpointStore};
+
+use async_trait::async_trait;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::task;
+use url::Url;
+
+/// Appends checkpoints to a file, one JSON object per line
+pub struct FileCheckpointStore(PathBuf);
+
+/// Write `line` to the end of `path`, on a line of its own even if the last
+/// write was torn
+fn append(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    let mut last = [b'\n'];
+    if file.metadata()?.len() > 0 {
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+    }
+    if last[0] != b'\n' {
+        file.write_all(b"\n")?;
+    }
+
+    file.write_all(line.as_bytes())?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn latest(path: &Path) -> Result<Option<Checkpoint>> {
+    let lines = match fs::read_to_string(path) {
+        Ok(lines) => lines,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // A torn line was never handed out, so skipping it is fine
+    Ok(lines
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str(line).ok()))
+}
+
+impl FileCheckpointStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self(path)
+    }
+
+    /// Keep `checkpoints` in the directory of a `file://` URL, next to the events
+    pub fn open(url: &Url) -> Result<Self> {
+        match url.to_file_path() {
+            Ok(dir) => {
+                fs::create_dir_all(&dir)?;
+                Ok(Self::new(dir.join("checkpoints")))
+            }
+            Err(_) => Err(EventStoreError::Database(format!("Invalid path in {}", url))),
+        }
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for FileCheckpointStore {
+    async fn add(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let path = self.0.clone();
+        let line = serde_json::to_string(checkpoint)
+            .map_err(|e| EventStoreError::Database(e.to_string()))?;
+
+        task::spawn_blocking(move || append(&path, &line)).await?
+    }
+
+    async fn latest(&self) -> Result<Option<Checkpoint>> {
+        let path = self.0.clone();
+        task::spawn_blocking(move || latest(&path)).await?
+    }
+}
@@ -0,0 +1,50 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{EventStoreError, Result};
+use crate::merkle::{Checkpoint, CheckpointStore};
+
+use async_trait::async_trait;
+use std::sync::{Mutex, MutexGuard};
+
+/// Keeps the latest checkpoint in process memory, for tests and local
+/// development
+#[derive(Default)]
+pub struct MemoryCheckpointStore(Mutex<Option<Checkpoint>>);
+
+impl MemoryCheckpointStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn checkpoint(&self) -> Result<MutexGuard<'_, Option<Checkpoint>>> {
+        self.0
+            .lock()
+            .map_err(|e| EventStoreError::Database(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MemoryCheckpointStore {
+    async fn add(&self, checkpoint: &Checkpoint) -> Result<()> {
+        *self.checkpoint()? = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    async fn latest(&self) -> Result<Option<Checkpoint>> {
+        Ok(self.checkpoint()?.clone())
+    }
+}
@@ -0,0 +1,74 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{is_duplicate, Result};
+use crate::merkle::{Checkpoint, CheckpointStore};
+
+use async_trait::async_trait;
+use bson::doc;
+use mongodb::options::FindOneOptions;
+use mongodb::Database;
+
+const CHECKPOINTS: &str = "checkpoints";
+
+pub struct MongoCheckpointStore(Database);
+
+impl MongoCheckpointStore {
+    pub fn new(database: Database) -> Self {
+        Self(database)
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MongoCheckpointStore {
+    async fn add(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let doc = doc! {
+            "_id": checkpoint.size as i64,
+            "root": &checkpoint.root,
+            "timestamp": checkpoint.timestamp,
+            "key": &checkpoint.key,
+            "signature": &checkpoint.signature,
+        };
+
+        match self.0.collection(CHECKPOINTS).insert_one(doc, None).await {
+            Ok(_) => Ok(()),
+            Err(ref e) if is_duplicate(e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn latest(&self) -> Result<Option<Checkpoint>> {
+        let options = FindOneOptions::builder()
+            .sort(Some(doc! { "_id": -1 }))
+            .build();
+
+        match self
+            .0
+            .collection(CHECKPOINTS)
+            .find_one(None, Some(options))
+            .await?
+        {
+            Some(doc) => Ok(Some(Checkpoint {
+                size: doc.get_i64("_id")? as usize,
+                root: doc.get_str("root")?.to_string(),
+                timestamp: *doc.get_utc_datetime("timestamp")?,
+                key: doc.get_str("key")?.to_string(),
+                signature: doc.get_str("signature")?.to_string(),
+            })),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::Result;
+use crate::merkle::{Checkpoint, CheckpointStore};
+use crate::schema::checkpoints::dsl;
+use crate::{blocking, PgPool};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use std::sync::Arc;
+
+pub struct PostgresCheckpointStore(Arc<PgPool>);
+
+impl PostgresCheckpointStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self(pool)
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for PostgresCheckpointStore {
+    async fn add(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let checkpoint = checkpoint.clone();
+
+        blocking(&self.0, move |conn| {
+            diesel::insert_into(dsl::checkpoints)
+                .values((
+                    dsl::size.eq(checkpoint.size as i64),
+                    dsl::root.eq(checkpoint.root),
+                    dsl::timestamp.eq(checkpoint.timestamp),
+                    dsl::key.eq(checkpoint.key),
+                    dsl::signature.eq(checkpoint.signature),
+                ))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn latest(&self) -> Result<Option<Checkpoint>> {
+        blocking(&self.0, |conn| {
+            Ok(dsl::checkpoints
+                .order(dsl::size.desc())
+                .first::<(i64, String, DateTime<Utc>, String, String)>(conn)
+                .optional()?
+                .map(|(size, root, timestamp, key, signature)| Checkpoint {
+                    size: size as usize,
+                    root,
+                    timestamp,
+                    key,
+                    signature,
+                }))
+        })
+        .await
+    }
+}
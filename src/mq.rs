@@ -14,12 +14,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::auth::{self, TokenStore};
+use crate::eventstore::EventStore;
+use crate::MAX_PAGE_SIZE;
+
+use futures::executor::block_on;
 use log::{debug, error, info};
 use rmp_serde as rmp;
 use serde::Deserialize;
 use std::net::Ipv4Addr;
-use std::sync::mpsc;
-use std::{thread, u16};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, oneshot};
 use url::Url;
 use zmq::{Context, Socket, SocketEvent};
 
@@ -33,11 +40,21 @@ pub struct Message {
 pub enum PublishMessage {
     Sequence(i64),
     Forward(Message),
+    /// Reports whether every sequence since the last flush made it onto the socket
+    Flush(oneshot::Sender<bool>),
 }
 
 pub type Tx = mpsc::Sender<PublishMessage>;
+pub type Notify = broadcast::Sender<i64>;
+
+const NOTIFY_CAPACITY: usize = 1024;
 
-fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failure::Error> {
+fn publish(
+    context: &Context,
+    address: Ipv4Addr,
+    port: u16,
+    notify: Notify,
+) -> Result<Tx, failure::Error> {
     let (tx, rx) = mpsc::channel::<PublishMessage>();
     let url = Url::parse(&format!("tcp://{}:{}", &address, &port))?;
 
@@ -51,6 +68,8 @@ fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failur
     info!("ØMQ publish socket listening on {}", &url);
 
     thread::spawn(move || {
+        let mut failed = false;
+
         for message in rx {
             match message {
                 PublishMessage::Sequence(id) => {
@@ -60,6 +79,7 @@ fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failur
                         Ok(bytes) => bytes,
                         Err(e) => {
                             error!("{}", e);
+                            failed = true;
                             continue;
                         }
                     };
@@ -69,7 +89,11 @@ fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failur
                         .and_then(|_| socket.send(&bytes, 0))
                     {
                         error!("{}", e);
+                        failed = true;
                     }
+
+                    // Nobody listening in-process is fine
+                    let _ = notify.send(id);
                 }
 
                 PublishMessage::Forward(Message { topic, data }) => {
@@ -82,6 +106,11 @@ fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failur
                         error!("{}", e);
                     }
                 }
+
+                PublishMessage::Flush(done) => {
+                    let _ = done.send(!failed);
+                    failed = false;
+                }
             }
         }
     });
@@ -89,6 +118,92 @@ fn publish(context: &Context, address: Ipv4Addr, port: u16) -> Result<Tx, failur
     Ok(tx)
 }
 
+/// The request frame of a replay request, once its token checks out
+fn admit<'a>(
+    runtime: &Handle,
+    tokens: Option<&dyn TokenStore>,
+    frames: &'a [Vec<u8>],
+) -> Result<&'a [u8], String> {
+    match (tokens, frames) {
+        (None, [.., request]) => Ok(request),
+        (None, []) => Err("Empty replay request".to_string()),
+        (Some(tokens), [token, request]) => {
+            let token = std::str::from_utf8(token).map_err(|_| "Invalid token".to_string())?;
+            runtime
+                .enter(|| block_on(auth::check(tokens, token, auth::EVENTS_READ)))
+                .map_err(|denied| denied.reason)?;
+            Ok(request)
+        }
+        (Some(_), _) => Err("Replay requests need a token frame first".to_string()),
+    }
+}
+
+/// Serve events since a msgpack-encoded sequence, one page per request, to
+/// subscribers that need to catch up on missed notifications. With a token
+/// store, the sequence has to follow a frame with a token that was granted
+/// `events:read`.
+fn replay(
+    context: &Context,
+    address: Ipv4Addr,
+    port: u16,
+    store: Arc<dyn EventStore>,
+    tokens: Option<Arc<dyn TokenStore>>,
+) -> Result<(), failure::Error> {
+    let url = Url::parse(&format!("tcp://{}:{}", &address, &port))?;
+
+    let socket = context.socket(zmq::REP)?;
+    socket.monitor(
+        "inproc://replay-monitor",
+        SocketEvent::ACCEPTED as i32 + SocketEvent::CLOSED as i32,
+    )?;
+    socket.bind(&url.clone().into_string())?;
+
+    info!("ØMQ replay socket listening on {}", &url);
+
+    // The store is async and this thread isn't, so borrow the caller's runtime
+    let runtime = Handle::current();
+
+    thread::spawn(move || loop {
+        let frames = match socket.recv_multipart(0) {
+            Ok(frames) => frames,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+
+        let reply = admit(&runtime, tokens.as_deref(), &frames)
+            .and_then(|request| {
+                rmp::from_read_ref::<_, i64>(&request)
+                    .map_err(|e| format!("Invalid replay request: {}", e))
+            })
+            .and_then(|from| {
+                debug!("Replay events since {}", from);
+                runtime
+                    .enter(|| block_on(store.retrieve_range(from, MAX_PAGE_SIZE)))
+                    .map_err(|e| e.to_string())
+            })
+            .and_then(|events| rmp::to_vec_named(&events).map_err(|e| e.to_string()));
+
+        let (topic, bytes) = match reply {
+            Ok(bytes) => ("events", bytes),
+            Err(message) => {
+                error!("{}", &message);
+                ("error", rmp::to_vec(&message).unwrap_or_default())
+            }
+        };
+
+        if let Err(e) = socket
+            .send(topic, zmq::SNDMORE)
+            .and_then(|_| socket.send(&bytes, 0))
+        {
+            error!("{}", e);
+        }
+    });
+
+    Ok(())
+}
+
 fn poll_monitor(name: String, monitor: Socket) {
     thread::spawn(move || loop {
         if let Ok(message) = monitor.recv_msg(0) {
@@ -104,14 +219,26 @@ fn poll_monitor(name: String, monitor: Socket) {
     });
 }
 
-pub fn launch(address: Ipv4Addr, port: u16) -> Result<Tx, failure::Error> {
+pub fn launch(
+    address: Ipv4Addr,
+    port: u16,
+    replay_port: u16,
+    store: Arc<dyn EventStore>,
+    tokens: Option<Arc<dyn TokenStore>>,
+) -> Result<(Tx, Notify), failure::Error> {
     let context = Context::new();
-    let tx = publish(&context, address, port)?;
+    let (notify, _) = broadcast::channel(NOTIFY_CAPACITY);
+    let tx = publish(&context, address, port, notify.clone())?;
+    replay(&context, address, replay_port, store, tokens)?;
 
     let publish_monitor = context.socket(zmq::PAIR)?;
     publish_monitor.connect("inproc://monitor")?;
 
+    let replay_monitor = context.socket(zmq::PAIR)?;
+    replay_monitor.connect("inproc://replay-monitor")?;
+
     poll_monitor("Publish".to_string(), publish_monitor);
+    poll_monitor("Replay".to_string(), replay_monitor);
 
-    Ok(tx)
+    Ok((tx, notify))
 }
@@ -0,0 +1,98 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::EventStore;
+use crate::mq::{PublishMessage, Tx};
+
+use log::{debug, error};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::delay_for;
+
+const BATCH_SIZE: i64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Hand pending sequences to the publisher and only acknowledge them once
+/// they are on the socket.
+async fn drain(store: &dyn EventStore, tx: &Mutex<Tx>) -> Result<(), String> {
+    loop {
+        let pending = store
+            .pending(BATCH_SIZE)
+            .await
+            .map_err(|e| e.to_string())?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let (flushed, done) = oneshot::channel();
+        {
+            let tx = tx.lock().map_err(|e| e.to_string())?;
+            for sequence in &pending {
+                tx.send(PublishMessage::Sequence(*sequence))
+                    .map_err(|e| format!("Couldn't notify: {:?}", e))?;
+            }
+            tx.send(PublishMessage::Flush(flushed))
+                .map_err(|e| format!("Couldn't flush: {:?}", e))?;
+        }
+
+        match done.await {
+            Ok(true) => {}
+            Ok(false) => return Err("Publishing failed".to_string()),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        store
+            .acknowledge(&pending)
+            .await
+            .map_err(|e| e.to_string())?;
+        debug!("Dispatched {} pending notifications", pending.len());
+    }
+}
+
+async fn dispatch(store: Arc<dyn EventStore>, tx: Arc<Mutex<Tx>>, wake: Arc<Notify>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match drain(&*store, &tx).await {
+            Ok(()) => {
+                backoff = MIN_BACKOFF;
+
+                // Polling picks up whatever another instance or a previous run left behind
+                tokio::select! {
+                    _ = wake.notified() => {}
+                    _ = delay_for(POLL_INTERVAL) => {}
+                }
+            }
+            Err(e) => {
+                error!("Couldn't dispatch pending notifications: {}", e);
+                delay_for(backoff).await;
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Start draining the outbox; notify the returned handle after appending
+/// events to have them dispatched right away.
+pub fn launch(store: Arc<dyn EventStore>, tx: Arc<Mutex<Tx>>) -> Arc<Notify> {
+    let wake = Arc::new(Notify::new());
+    tokio::spawn(dispatch(store, tx, wake.clone()));
+    wake
+}
@@ -0,0 +1,132 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Tables as created by this crate's migrations
+
+table! {
+    events (sequence) {
+        sequence -> Int8,
+        version -> Int4,
+        #[sql_name = "type"]
+        type_ -> Varchar,
+        data -> Jsonb,
+        timestamp -> Timestamptz,
+        stream -> Nullable<Varchar>,
+        revision -> Nullable<Int8>,
+        hash -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    event_head (id) {
+        id -> Bool,
+        sequence -> Int8,
+        hash -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    outbox (sequence) {
+        sequence -> Int8,
+    }
+}
+
+table! {
+    datastore (hash) {
+        hash -> Bytea,
+        data -> Bytea,
+        created -> Timestamptz,
+    }
+}
+
+table! {
+    keys (subject) {
+        subject -> Varchar,
+        key -> Bytea,
+    }
+}
+
+table! {
+    tokens (hash) {
+        hash -> Bytea,
+        scopes -> Varchar,
+    }
+}
+
+table! {
+    checkpoints (size) {
+        size -> Int8,
+        root -> Varchar,
+        timestamp -> Timestamptz,
+        key -> Varchar,
+        signature -> Varchar,
+    }
+}
+
+/// The same tables for the SQLite backend, see migrations-sqlite
+pub mod sqlite {
+    table! {
+        events (sequence) {
+            sequence -> BigInt,
+            version -> Integer,
+            #[sql_name = "type"]
+            type_ -> Text,
+            data -> Text,
+            timestamp -> Timestamp,
+            stream -> Nullable<Text>,
+            revision -> Nullable<BigInt>,
+            hash -> Nullable<Binary>,
+        }
+    }
+
+    table! {
+        outbox (sequence) {
+            sequence -> BigInt,
+        }
+    }
+
+    table! {
+        datastore (hash) {
+            hash -> Binary,
+            data -> Binary,
+            created -> Timestamp,
+        }
+    }
+
+    table! {
+        keys (subject) {
+            subject -> Text,
+            key -> Binary,
+        }
+    }
+
+    table! {
+        tokens (hash) {
+            hash -> Binary,
+            scopes -> Text,
+        }
+    }
+
+    table! {
+        checkpoints (size) {
+            size -> BigInt,
+            root -> Text,
+            timestamp -> Timestamp,
+            key -> Text,
+            signature -> Text,
+        }
+    }
+}
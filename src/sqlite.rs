@@ -0,0 +1,47 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::SqlitePool;
+
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error, Pool};
+use diesel::SqliteConnection;
+
+embed_migrations!("migrations-sqlite");
+
+#[derive(Debug)]
+struct Pragmas;
+
+impl CustomizeConnection<SqliteConnection, Error> for Pragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), Error> {
+        // Wait for concurrent writers instead of failing with SQLITE_BUSY
+        conn.batch_execute("PRAGMA busy_timeout = 5000; PRAGMA foreign_keys = ON;")
+            .map_err(Error::QueryError)
+    }
+}
+
+/// Open the database at `path`, creating it and its tables if necessary
+pub fn connect(path: &str) -> Result<SqlitePool, failure::Error> {
+    let pool = Pool::builder()
+        .connection_customizer(Box::new(Pragmas))
+        .build(ConnectionManager::<SqliteConnection>::new(path))?;
+
+    let conn = pool.get()?;
+    conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+    embedded_migrations::run(&*conn)?;
+
+    Ok(pool)
+}
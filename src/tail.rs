@@ -0,0 +1,82 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::EventStore;
+use crate::MAX_PAGE_SIZE;
+
+use futures::stream::{self, Stream};
+use log::error;
+use reactrix::Event;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::broadcast::{Receiver, RecvError};
+
+struct Tail {
+    store: Arc<dyn EventStore>,
+    notifications: Receiver<i64>,
+    next: i64,
+    buffer: VecDeque<Event>,
+}
+
+/// Replay stored events starting at `from`, then follow new ones as they are
+/// announced. The receiver has to be subscribed before the replay starts so
+/// that nothing appended in between is missed; notifications only ever
+/// trigger another read from the store, which keeps the stream gap-free even
+/// if the receiver lags behind.
+pub fn tail(
+    store: Arc<dyn EventStore>,
+    notifications: Receiver<i64>,
+    from: i64,
+) -> impl Stream<Item = Event> {
+    let state = Tail {
+        store,
+        notifications,
+        next: from,
+        buffer: VecDeque::new(),
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.buffer.pop_front() {
+                return Some((event, state));
+            }
+
+            match state.store.retrieve_range(state.next, MAX_PAGE_SIZE).await {
+                Ok(events) => {
+                    if let Some(last) = events.last() {
+                        // Nothing below `last` can show up later, see
+                        // `EventStore::retrieve_range`
+                        state.next = last.sequence + 1;
+                        state.buffer.extend(events);
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't read events from {}: {}", state.next, e);
+                    return None;
+                }
+            }
+
+            loop {
+                match state.notifications.recv().await {
+                    Ok(sequence) if sequence < state.next => continue,
+                    Ok(_) | Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
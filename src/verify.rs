@@ -0,0 +1,179 @@
+// This file is part of reactrix-store.
+//
+// Copyright 2020 Alexander Dorn
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::eventstore::{chain_hash, EventStore, EventStoreError, Result};
+use crate::MAX_PAGE_SIZE;
+
+use reactrix::Event;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize)]
+pub struct Broken {
+    pub sequence: i64,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub checked: i64,
+    /// Events from before the chain started, which nothing vouches for
+    pub unchained: i64,
+    /// The first link that doesn't hold, if any
+    pub broken: Option<Broken>,
+}
+
+fn broken(checked: i64, unchained: i64, sequence: i64, reason: &str) -> Report {
+    Report {
+        checked,
+        unchained,
+        broken: Some(Broken {
+            sequence,
+            reason: reason.to_string(),
+        }),
+    }
+}
+
+/// Recompute the hash chain from `from` to `to`, both inclusive. The stored
+/// hash just before `from` is taken on trust; without one right there, the
+/// first hash in the range is.
+pub async fn verify(store: &dyn EventStore, from: i64, to: i64) -> Result<Report> {
+    let from = from.max(0);
+    let (mut previous, mut trusted) = match store.links(from - 1, 1).await?.pop() {
+        Some(link) if link.sequence == from - 1 => (link.hash, false),
+        _ => (None, from > 0),
+    };
+
+    let mut checked = 0;
+    let mut unchained = 0;
+    let mut next = from;
+
+    while next <= to {
+        let links = store.links(next, MAX_PAGE_SIZE).await?;
+        let mut events = store
+            .retrieve_range(next, MAX_PAGE_SIZE)
+            .await?
+            .into_iter()
+            .map(|event| (event.sequence, event))
+            .collect::<HashMap<i64, Event>>();
+
+        let last = match links.last() {
+            Some(link) => link.sequence,
+            None => break,
+        };
+
+        for link in links.into_iter().take_while(|link| link.sequence <= to) {
+            let hash = match link.hash {
+                Some(hash) => hash,
+                // Events from before the chain was introduced
+                None if previous.is_none() => {
+                    unchained += 1;
+                    continue;
+                }
+                None => {
+                    return Ok(broken(checked, unchained, link.sequence, "Hash is missing"))
+                }
+            };
+
+            if !trusted {
+                let event = match events.remove(&link.sequence) {
+                    Some(event) => event,
+                    None => match store.retrieve(link.sequence).await {
+                        Ok(event) => event,
+                        Err(EventStoreError::NoRecord) => {
+                            return Ok(broken(
+                                checked,
+                                unchained,
+                                link.sequence,
+                                "Event is missing",
+                            ))
+                        }
+                        Err(e) => return Err(e),
+                    },
+                };
+
+                if chain_hash(previous.as_deref(), &event) != hash {
+                    let reason = "Hash doesn't match";
+                    return Ok(broken(checked, unchained, link.sequence, reason));
+                }
+            }
+
+            checked += 1;
+            trusted = false;
+            previous = Some(hash);
+        }
+
+        next = last + 1;
+    }
+
+    Ok(Report {
+        checked,
+        unchained,
+        broken: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventstore::{MemoryEventStore, ShreddingEventStore};
+    use crate::keystore::MemoryKeyStore;
+    use reactrix::NewEvent;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    async fn fill(store: &dyn EventStore, data: Vec<Value>) {
+        for data in data {
+            let event = NewEvent {
+                version: 1,
+                type_: "test".to_string(),
+                data,
+            };
+            store.store(event, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn intact_chains_hold() {
+        let store = MemoryEventStore::new();
+        fill(&store, (0..5).map(|i| json!({ "i": i })).collect()).await;
+
+        let report = verify(&store, 0, 10).await.unwrap();
+        assert_eq!((report.checked, report.unchained), (5, 0));
+        assert!(report.broken.is_none());
+
+        // The link just before the range is taken on trust
+        let report = verify(&store, 2, 3).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.broken.is_none());
+    }
+
+    #[tokio::test]
+    async fn events_are_checked_as_stored() {
+        let inner = Arc::new(MemoryEventStore::new());
+        let keys = Arc::new(MemoryKeyStore::new());
+        let store = ShreddingEventStore::new(inner.clone(), keys).unwrap();
+        let personal = json!({ "name": { "@subject": "alice", "@personal": "Alice" } });
+        fill(&store, vec![json!({ "i": 0 }), personal]).await;
+
+        assert!(verify(&*inner, 0, 10).await.unwrap().broken.is_none());
+
+        // Unsealed, the personal data no longer matches its hash
+        let report = verify(&store, 0, 10).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.broken.unwrap().sequence, 1);
+    }
+}